@@ -40,9 +40,11 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     fastrace::set_reporter(ConsoleReporter, Config::default());
 
     // Build and start the server with the fastrace server layer.
-    // This layer will extract trace context from incoming requests.
+    // This layer will extract trace context from incoming requests, and record the peer
+    // address from the connect info that `serve` inserts into the request extensions.
+    let layer = fastrace_tonic::ServerTracing::new().build();
     Server::builder()
-        .layer(fastrace_tonic::FastraceServerLayer::default())
+        .layer(layer)
         .add_service(PingServer::new(MyPing::default()))
         .serve("[::1]:50051".parse().unwrap())
         .await?;
@@ -0,0 +1,315 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::task::ready;
+
+use bytes::Buf;
+use fastrace::prelude::*;
+use http_body::Body;
+use http_body::Frame;
+use http_body::SizeHint;
+
+use crate::RequestSpan;
+use crate::StatusInfo;
+use crate::server::ServerConfig;
+
+pin_project_lite::pin_project! {
+    /// Request body passed by [`FastraceServerService`](crate::FastraceServerService) to the
+    /// inner service.
+    ///
+    /// The request span is kept open until the body is finished or dropped, so it covers the
+    /// whole upload of client-streaming requests, and a `request complete` event with the
+    /// `elapsed_us` since the request started is added once the last message is received.
+    ///
+    /// Requests that are not traced, such as those excluded by a filter, get the same type so
+    /// the inner service sees a single body type, but their body is passed through untouched.
+    /// Tonic's router accepts any body with `Bytes` data, so the layer is added with
+    /// `Server::layer` as before; only inner services naming `BoxBody` as their request body
+    /// need to accept `FastraceRequestBody<BoxBody>` instead.
+    pub struct FastraceRequestBody<B> {
+        #[pin]
+        inner: B,
+        span: Option<RequestSpan>,
+        // `None` unless message events are enabled.
+        messages: Option<u64>,
+    }
+}
+
+impl<B> FastraceRequestBody<B> {
+    pub(crate) fn new(inner: B, span: RequestSpan, message_events: bool) -> Self {
+        Self {
+            inner,
+            span: Some(span),
+            messages: message_events.then_some(0),
+        }
+    }
+
+    /// Wraps the body of a request that is not traced, passing it through untouched.
+    pub(crate) fn passthrough(inner: B) -> Self {
+        Self {
+            inner,
+            span: None,
+            messages: None,
+        }
+    }
+}
+
+impl<B: Body> Body for FastraceRequestBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let frame = ready!(this.inner.poll_frame(cx));
+
+        match &frame {
+            Some(Ok(frame)) => {
+                if let (Some(data), Some(span)) = (frame.data_ref(), this.span.as_ref()) {
+                    span.count_request_frame(data.remaining());
+                    if let Some(messages) = this.messages.as_mut() {
+                        add_message_event(span, "message received", messages, data.remaining());
+                    }
+                }
+            }
+            Some(Err(_)) => {
+                if let Some(span) = this.span.take() {
+                    span.add_event(Event::new("request body failed"));
+                }
+            }
+            None => {
+                if let Some(span) = this.span.take() {
+                    span.checkpoint("request complete");
+                }
+            }
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Response body of [`FastraceServerService`](crate::FastraceServerService).
+    ///
+    /// The request span is kept open until the body is finished or dropped, so it covers
+    /// streaming responses entirely, and the `grpc-status` and `grpc-message` trailers sent
+    /// after the last message are recorded on it, along with a `trailers sent` event. A
+    /// `response complete` event marks the end of the body, separating the handler returning
+    /// from the response being fully handed to the server. The body is polled with the request span, or
+    /// the `response body` span enabled by
+    /// [`ServerTracing::with_response_body_span`](crate::ServerTracing::with_response_body_span),
+    /// as the local parent. If the body is dropped before it is finished, the span gets a
+    /// `cancelled` property and event.
+    pub struct FastraceServerBody<B> {
+        #[pin]
+        inner: B,
+        // Declared before `span`, so the child span ends first when the body is dropped.
+        body_span: Option<Span>,
+        span: Option<RequestSpan>,
+        // `None` unless message events are enabled.
+        messages: Option<u64>,
+        awaiting_first_byte: bool,
+        status: http::StatusCode,
+        config: Arc<ServerConfig>,
+    }
+
+    impl<B> PinnedDrop for FastraceServerBody<B> {
+        fn drop(this: Pin<&mut Self>) {
+            let this = this.project();
+            this.body_span.take();
+            if let Some(span) = this.span.take() {
+                span.record_cancelled();
+            }
+        }
+    }
+}
+
+impl<B: Body> FastraceServerBody<B> {
+    pub(crate) fn new(
+        inner: B,
+        span: Option<RequestSpan>,
+        status: http::StatusCode,
+        config: Arc<ServerConfig>,
+    ) -> Self {
+        // A body ending right away, such as the one of a Trailers-Only response, is not polled.
+        let span = span.filter(|_| !inner.is_end_stream());
+        let body_span = span
+            .as_ref()
+            .filter(|_| config.response_body_span)
+            .map(|span| Span::enter_with_parent("response body", span));
+        Self {
+            inner,
+            body_span,
+            span,
+            messages: config.message_events.then_some(0),
+            awaiting_first_byte: config.time_to_first_byte,
+            status,
+            config,
+        }
+    }
+}
+
+impl<B: Body> Body for FastraceServerBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+
+        let guard = match this.body_span {
+            Some(body_span) => Some(body_span.set_local_parent()),
+            None => this.span.as_ref().map(|span| span.set_local_parent()),
+        };
+        let frame = ready!(this.inner.as_mut().poll_frame(cx));
+        drop(guard);
+
+        match &frame {
+            Some(Ok(frame)) => {
+                if let (Some(data), Some(span)) = (frame.data_ref(), this.span.as_ref()) {
+                    if std::mem::take(this.awaiting_first_byte) {
+                        span.checkpoint("first byte");
+                    }
+                    span.count_response_frame(data.remaining());
+                    if let Some(messages) = this.messages.as_mut() {
+                        add_message_event(span, "message sent", messages, data.remaining());
+                    }
+                }
+                if let (Some(trailers), Some(span)) = (frame.trailers_ref(), this.span.as_ref()) {
+                    span.checkpoint("trailers sent");
+                    this.config
+                        .record_status(span, StatusInfo::new(*this.status, trailers));
+                }
+                // The body may not be polled again once it reports the end of the stream.
+                if this.inner.is_end_stream() {
+                    this.body_span.take();
+                    if let Some(span) = this.span.take() {
+                        span.checkpoint("response complete");
+                    }
+                }
+            }
+            Some(Err(_)) => {
+                this.body_span.take();
+                if let Some(span) = this.span.take() {
+                    span.add_event(Event::new("response body failed"));
+                }
+            }
+            None => {
+                this.body_span.take();
+                if let Some(span) = this.span.take() {
+                    span.checkpoint("response complete");
+                }
+            }
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Adds an event for a data frame, with its index in the body and its size in bytes.
+fn add_message_event(span: &Span, name: &'static str, messages: &mut u64, size: usize) {
+    let index = *messages;
+    *messages += 1;
+    span.add_event(
+        Event::new(name)
+            .with_properties(|| [("index", index.to_string()), ("size", size.to_string())]),
+    );
+}
+
+pin_project_lite::pin_project! {
+    /// Response body of [`FastraceClientSpanService`](crate::FastraceClientSpanService).
+    ///
+    /// The client span is kept open until the body is finished or dropped, and the `grpc-status`
+    /// trailer is recorded on it. The body of requests without a span, such as those rejected by
+    /// a filter, is passed through untouched.
+    pub struct FastraceClientBody<B> {
+        #[pin]
+        inner: B,
+        span: Option<Arc<Span>>,
+    }
+}
+
+impl<B: Body> FastraceClientBody<B> {
+    pub(crate) fn new(inner: B, span: Option<Arc<Span>>) -> Self {
+        // A body ending right away, such as the one of a Trailers-Only response, may not be
+        // polled.
+        let span = span.filter(|_| !inner.is_end_stream());
+        Self { inner, span }
+    }
+}
+
+impl<B: Body> Body for FastraceClientBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let mut this = self.project();
+
+        let frame = ready!(this.inner.as_mut().poll_frame(cx));
+
+        match &frame {
+            Some(Ok(frame)) => {
+                if let (Some(trailers), Some(span)) = (frame.trailers_ref(), this.span.as_ref()) {
+                    record_client_status(span, trailers);
+                }
+                // The body may not be polled again once it reports the end of the stream.
+                if this.inner.is_end_stream() {
+                    this.span.take();
+                }
+            }
+            Some(Err(_)) => {
+                if let Some(span) = this.span.take() {
+                    span.add_property(|| ("error", "true"));
+                    span.add_event(Event::new("response body failed"));
+                }
+            }
+            None => {
+                this.span.take();
+            }
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+/// Records the `grpc-status` of a response head or trailers on a client span.
+pub(crate) fn record_client_status(span: &Span, headers: &http::HeaderMap) {
+    if let Some(code) = crate::status::grpc_status(headers) {
+        span.add_property(|| ("rpc.grpc.status_code", code.to_string()));
+        if code != 0 {
+            span.add_property(|| ("error", "true"));
+        }
+    }
+}
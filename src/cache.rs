@@ -0,0 +1,33 @@
+/// Whether a response was served from a cache.
+///
+/// Inner layers and handlers can insert this into the response extensions, and
+/// [`FastraceServerLayer`](crate::FastraceServerLayer) records it on the request span as the
+/// `cache.status` property before the span is submitted.
+///
+/// ```
+/// use fastrace_tonic::CacheStatus;
+///
+/// let mut response = http::Response::new(());
+/// response.extensions_mut().insert(CacheStatus::Hit);
+/// ```
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum CacheStatus {
+    /// The response was served from the cache.
+    Hit,
+    /// The response was not found in the cache and was computed.
+    Miss,
+    /// The cache was not consulted.
+    Bypass,
+}
+
+impl CacheStatus {
+    /// Returns the value recorded on the span.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Hit => "hit",
+            Self::Miss => "miss",
+            Self::Bypass => "bypass",
+        }
+    }
+}
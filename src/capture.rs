@@ -0,0 +1,24 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+
+use fastrace::prelude::*;
+
+pub(crate) type Redactor = Arc<dyn Fn(&str, &str) -> Option<String> + Send + Sync + 'static>;
+
+/// Adds a captured value as a span property, unless `redactor` drops it.
+pub(crate) fn add_property(
+    span: &Span,
+    redactor: Option<&Redactor>,
+    key: impl Into<Cow<'static, str>>,
+    value: &str,
+) {
+    let key = key.into();
+    let value = match redactor {
+        Some(redactor) => match redactor(&key, value) {
+            Some(value) => value,
+            None => return,
+        },
+        None => value.to_owned(),
+    };
+    span.add_property(|| (key, value));
+}
@@ -0,0 +1,72 @@
+use std::cell::RefCell;
+use std::sync::Arc;
+
+use http::HeaderName;
+use http::HeaderValue;
+
+use crate::propagation;
+
+thread_local! {
+    static CURRENT: RefCell<Option<Arc<Carrier>>> = const { RefCell::new(None) };
+}
+
+/// Headers received by the server layer that the client layer forwards on downstream calls.
+///
+/// Like fastrace's local parent, the carrier is installed in a thread local while the request
+/// future is polled, so it is visible to client calls made from the request handler.
+#[derive(Debug, Default)]
+pub(crate) struct Carrier {
+    headers: Vec<(HeaderName, HeaderValue)>,
+}
+
+impl Carrier {
+    pub(crate) fn push(&mut self, name: HeaderName, value: HeaderValue) {
+        self.headers.push((name, value));
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.headers.is_empty()
+    }
+
+    /// Installs the carrier as the current one until the guard is dropped.
+    pub(crate) fn set_current(self: &Arc<Self>) -> CarrierGuard {
+        let prev = CURRENT.with(|current| current.replace(Some(self.clone())));
+        CarrierGuard { prev }
+    }
+
+    /// Returns whether the current carrier forwards a valid trace context, such as the one
+    /// installed by [`FastracePropagationLayer`](crate::FastracePropagationLayer).
+    pub(crate) fn current_carries_context() -> bool {
+        CURRENT.with(|current| {
+            current.borrow().as_ref().is_some_and(|carrier| {
+                propagation::extract_with(|name| {
+                    let (_, value) = carrier.headers.iter().find(|(header, _)| header == name)?;
+                    value.to_str().ok()
+                })
+                .is_some()
+            })
+        })
+    }
+
+    /// Calls `f` for each header of the current carrier.
+    pub(crate) fn for_each_current(mut f: impl FnMut(&HeaderName, &HeaderValue)) {
+        CURRENT.with(|current| {
+            if let Some(carrier) = &*current.borrow() {
+                for (name, value) in &carrier.headers {
+                    f(name, value);
+                }
+            }
+        });
+    }
+}
+
+pub(crate) struct CarrierGuard {
+    prev: Option<Arc<Carrier>>,
+}
+
+impl Drop for CarrierGuard {
+    fn drop(&mut self) {
+        let prev = self.prev.take();
+        CURRENT.with(|current| *current.borrow_mut() = prev);
+    }
+}
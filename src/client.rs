@@ -0,0 +1,758 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::task::ready;
+
+use fastrace::prelude::*;
+use http::HeaderName;
+use http::HeaderValue;
+use http::Request;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::FastraceClientBody;
+use crate::FastraceToggle;
+use crate::ForwardingRules;
+use crate::TRACEPARENT_HEADER;
+use crate::body::record_client_status;
+use crate::carrier::Carrier;
+use crate::propagation;
+
+type RequestFilter = Arc<dyn Fn(&http::request::Parts) -> bool + Send + Sync + 'static>;
+
+type SpanContextInjector = Arc<dyn Fn(&SpanContext, &mut http::HeaderMap) + Send + Sync + 'static>;
+
+type ParentSupplier =
+    Arc<dyn Fn(&http::request::Parts) -> Option<SpanContext> + Send + Sync + 'static>;
+
+/// What the client layer does when an outgoing request is made without a local parent.
+///
+/// A supplier configured with [`ClientTracing::with_missing_parent_context`] takes precedence.
+/// Neither applies when the request being handled forwards its trace context, such as through
+/// [`FastracePropagationLayer`](crate::FastracePropagationLayer): the forwarded context is sent
+/// unchanged.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingParentPolicy {
+    /// Do not inject a span context. Forwarded headers are still injected.
+    #[default]
+    Skip,
+    /// Inject a random span context, starting a new trace at the receiving server.
+    NewTrace,
+}
+
+/// What the client layer does when an outgoing request already carries a trace context, such as
+/// a `traceparent` set by application code or by an upstream layer.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ExistingContextPolicy {
+    /// Replace the existing context.
+    #[default]
+    Overwrite,
+    /// Keep the existing context and inject nothing.
+    KeepExisting,
+    /// Like [`ExistingContextPolicy::KeepExisting`], adding an `existing trace context kept` event
+    /// to the current local parent.
+    ///
+    /// The layer does not fail such requests, since it shares the error type of the inner
+    /// service; the event makes unexpected contexts visible instead.
+    Report,
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct ClientConfig {
+    forwarding_rules: Vec<(String, ForwardingRules)>,
+    default_forwarding_rules: Option<ForwardingRules>,
+    filter: Option<RequestFilter>,
+    toggle: Option<FastraceToggle>,
+    traceparent_header: Option<HeaderName>,
+    missing_parent_policy: MissingParentPolicy,
+    missing_parent_context: Option<ParentSupplier>,
+    existing_context_policy: ExistingContextPolicy,
+    span_context_injector: Option<SpanContextInjector>,
+    /// The headers written by [`ClientConfig::inject`], given with the injector or filled in
+    /// when the layer is built.
+    injected_headers: Vec<HeaderName>,
+}
+
+/// Builder for [`FastraceClientLayer`].
+///
+/// All options have sensible defaults, so `ClientTracing::new().build()` is equivalent to
+/// `FastraceClientLayer::default()`.
+#[derive(Clone, Default)]
+pub struct ClientTracing {
+    config: ClientConfig,
+}
+
+impl ClientTracing {
+    /// Creates a builder with the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restrict the forwarded headers and baggage entries sent to `authority`.
+    ///
+    /// `authority` is matched against the authority of the request URI, either with or without
+    /// the port. Rules are checked in the order they were added and the first match applies. See
+    /// [`ForwardingRules`].
+    pub fn with_forwarding_rules(
+        mut self,
+        authority: impl Into<String>,
+        rules: ForwardingRules,
+    ) -> Self {
+        self.config.forwarding_rules.push((authority.into(), rules));
+        self
+    }
+
+    /// Restrict the forwarded headers and baggage entries of requests matching no authority
+    /// rule.
+    ///
+    /// Tonic clients only set the authority of the request URI inside the channel, so a layer
+    /// added on top of a channel sees no authority. Use these rules for a layer dedicated to one
+    /// destination.
+    pub fn with_default_forwarding_rules(mut self, rules: ForwardingRules) -> Self {
+        self.config.default_forwarding_rules = Some(rules);
+        self
+    }
+
+    /// Configure a custom span context injector.
+    ///
+    /// The injector replaces the formats selected by cargo features and
+    /// [`ClientTracing::with_traceparent_header`], for receivers expecting a proprietary header
+    /// format. Forwarded headers are still injected. `headers` are the names of the headers the
+    /// injector writes, telling [`ClientTracing::with_existing_context_policy`] which headers
+    /// carry an existing context.
+    ///
+    /// ```
+    /// use http::HeaderName;
+    ///
+    /// let layer = fastrace_tonic::ClientTracing::new()
+    ///     .with_span_context_injector(
+    ///         [HeaderName::from_static("x-trace-id")],
+    ///         |span_context, headers| {
+    ///             let trace_id = format!("{:032x}", span_context.trace_id.0);
+    ///             if let Ok(value) = trace_id.parse() {
+    ///                 headers.insert("x-trace-id", value);
+    ///             }
+    ///         },
+    ///     )
+    ///     .build();
+    /// ```
+    pub fn with_span_context_injector<I, F>(mut self, headers: I, f: F) -> Self
+    where
+        I: IntoIterator<Item = HeaderName>,
+        F: Fn(&SpanContext, &mut http::HeaderMap) + Send + Sync + 'static,
+    {
+        self.config.span_context_injector = Some(Arc::new(f));
+        self.config.injected_headers = headers.into_iter().collect();
+        self
+    }
+
+    /// Configure a filter deciding which requests get the trace context.
+    ///
+    /// The filter receives the request head. When it returns `false`, the request is passed
+    /// through untouched: neither the span context nor the forwarded headers are injected. This
+    /// keeps trace headers from leaking to third-party endpoints.
+    pub fn with_filter<F>(mut self, f: F) -> Self
+    where F: Fn(&http::request::Parts) -> bool + Send + Sync + 'static {
+        self.config.filter = Some(Arc::new(f));
+        self
+    }
+
+    /// Turn injection on and off at runtime with `toggle`.
+    ///
+    /// While the toggle is off, requests are passed through as if rejected by a filter. See
+    /// [`FastraceToggle`].
+    pub fn with_toggle(mut self, toggle: FastraceToggle) -> Self {
+        self.config.toggle = Some(toggle);
+        self
+    }
+
+    /// Write the W3C traceparent under `header` instead of `traceparent`.
+    ///
+    /// This is meant for receivers behind proxies that strip or rewrite the standard header.
+    /// Other propagation headers are not affected.
+    pub fn with_traceparent_header(mut self, header: HeaderName) -> Self {
+        self.config.traceparent_header = Some(header);
+        self
+    }
+
+    /// Configure what happens when a request is made without a local parent, such as from a
+    /// task that is not traced.
+    ///
+    /// Defaults to [`MissingParentPolicy::Skip`].
+    pub fn with_missing_parent_policy(mut self, policy: MissingParentPolicy) -> Self {
+        self.config.missing_parent_policy = policy;
+        self
+    }
+
+    /// Configure a supplier of the span context to inject when a request is made without a local
+    /// parent.
+    ///
+    /// The supplier receives the request head. Return `None` to inject no span context. It takes
+    /// precedence over [`ClientTracing::with_missing_parent_policy`].
+    pub fn with_missing_parent_context<F>(mut self, f: F) -> Self
+    where F: Fn(&http::request::Parts) -> Option<SpanContext> + Send + Sync + 'static {
+        self.config.missing_parent_context = Some(Arc::new(f));
+        self
+    }
+
+    /// Configure what happens when a request already carries a trace context.
+    ///
+    /// Any header the layer would inject counts as an existing context: the `traceparent`
+    /// header, or the one set with [`ClientTracing::with_traceparent_header`], and the B3
+    /// headers when the `b3` feature is enabled. With
+    /// [`ClientTracing::with_span_context_injector`], these are the headers given along with the
+    /// injector. Defaults to [`ExistingContextPolicy::Overwrite`].
+    pub fn with_existing_context_policy(mut self, policy: ExistingContextPolicy) -> Self {
+        self.config.existing_context_policy = policy;
+        self
+    }
+
+    /// Builds the client layer.
+    pub fn build(self) -> FastraceClientLayer {
+        FastraceClientLayer {
+            config: self.into_config(),
+        }
+    }
+
+    /// Builds a client layer also creating a client span for every outgoing request.
+    ///
+    /// The span is a child of the current local parent, named after the request path, such as
+    /// `/helloworld.Greeter/SayHello`, and has the `span.kind` property set to `client`. Its
+    /// context is injected instead of the local parent, so it parents the span of the receiving
+    /// server, and it ends once the response body is finished or dropped, recording the
+    /// `http.response.status_code` and `rpc.grpc.status_code` properties. Failed calls get an
+    /// `error` property. Without a local parent, the span is a root span under the context of
+    /// [`ClientTracing::with_missing_parent_policy`], if any.
+    ///
+    /// Keeping the span open requires wrapping the response body in [`FastraceClientBody`], so
+    /// the services of this layer change the response type, unlike those of
+    /// [`ClientTracing::build`]. They only handle requests of `http` 1.
+    pub fn build_with_client_span(self) -> FastraceClientSpanLayer {
+        FastraceClientSpanLayer {
+            config: self.into_config(),
+        }
+    }
+
+    fn into_config(mut self) -> Arc<ClientConfig> {
+        if self.config.span_context_injector.is_none() {
+            // The built-in formats only write their headers, whatever the span context.
+            let mut injected = Vec::new();
+            propagation::inject_with(&SpanContext::new(TraceId(1), SpanId(1)), |name, _| {
+                injected.push(self.config.header_name(name));
+            });
+            self.config.injected_headers = injected;
+        }
+        Arc::new(self.config)
+    }
+}
+
+/// Client layer for injecting trace context into outgoing requests.
+///
+/// This layer adds the current trace context to outgoing requests,
+/// allowing the receiving service to continue the same trace. Add this
+/// to your tonic client to automatically propagate trace context.
+///
+/// When the call is made while handling a request traced by
+/// [`FastraceServerLayer`](crate::FastraceServerLayer), the headers it is configured to forward,
+/// such as the flavor header, are copied onto the outgoing request unless already present.
+///
+/// A [`SpanContext`] found in the request extensions is used as the parent instead of the
+/// current local parent. This keeps the intended parent when requests are built in one task and
+/// sent from another, such as through a `tower::buffer::Buffer`:
+///
+/// ```
+/// use fastrace::prelude::*;
+///
+/// let mut request = http::Request::new(());
+/// if let Some(parent) = SpanContext::current_local_parent() {
+///     request.extensions_mut().insert(parent);
+/// }
+/// ```
+///
+/// Use [`ClientTracing`] to configure the layer.
+#[derive(Clone, Default)]
+pub struct FastraceClientLayer {
+    config: Arc<ClientConfig>,
+}
+
+impl<S> Layer<S> for FastraceClientLayer {
+    type Service = FastraceClientService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        FastraceClientService {
+            service,
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// Client-side service that handles trace context propagation.
+///
+/// This service injects the current trace context into outgoing requests,
+/// allowing distributed tracing across service boundaries.
+#[derive(Clone)]
+pub struct FastraceClientService<S> {
+    pub(crate) service: S,
+    pub(crate) config: Arc<ClientConfig>,
+}
+
+impl<S, Body> Service<Request<Body>> for FastraceClientService<S>
+where S: Service<Request<Body>>
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let (req, _) = self.config.trace(req, false);
+        self.service.call(req)
+    }
+}
+
+/// Client layer creating a client span for every outgoing request, besides injecting the trace
+/// context like [`FastraceClientLayer`].
+///
+/// Use [`ClientTracing::build_with_client_span`] to create the layer.
+#[derive(Clone)]
+pub struct FastraceClientSpanLayer {
+    config: Arc<ClientConfig>,
+}
+
+impl<S> Layer<S> for FastraceClientSpanLayer {
+    type Service = FastraceClientSpanService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        FastraceClientSpanService {
+            service,
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// Client-side service creating a client span for every outgoing request.
+///
+/// See [`ClientTracing::build_with_client_span`] for details.
+#[derive(Clone)]
+pub struct FastraceClientSpanService<S> {
+    service: S,
+    config: Arc<ClientConfig>,
+}
+
+impl<S, Body, ResBody> Service<Request<Body>> for FastraceClientSpanService<S>
+where
+    S: Service<Request<Body>, Response = http::Response<ResBody>>,
+    ResBody: http_body::Body,
+{
+    type Response = http::Response<FastraceClientBody<ResBody>>;
+    type Error = S::Error;
+    type Future = FastraceClientFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<Body>) -> Self::Future {
+        let (req, span) = self.config.trace(req, true);
+        let span = span.map(Arc::new);
+        #[cfg(feature = "tonic")]
+        let req = {
+            let mut req = req;
+            if let Some(span) = &span {
+                req.extensions_mut()
+                    .insert(ClientSpan(Arc::downgrade(span)));
+            }
+            req
+        };
+        FastraceClientFuture {
+            inner: self.service.call(req),
+            span,
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Response future of [`FastraceClientSpanService`].
+    pub struct FastraceClientFuture<F> {
+        #[pin]
+        inner: F,
+        span: Option<Arc<Span>>,
+    }
+}
+
+/// The client span of a request, inserted into its extensions by [`FastraceClientSpanService`]
+/// for the layers below, such as
+/// [`FastraceUploadProgressLayer`](crate::FastraceUploadProgressLayer).
+#[cfg(feature = "tonic")]
+#[derive(Clone)]
+pub(crate) struct ClientSpan(pub(crate) std::sync::Weak<Span>);
+
+impl<F, ResBody, E> Future for FastraceClientFuture<F>
+where
+    F: Future<Output = Result<http::Response<ResBody>, E>>,
+    ResBody: http_body::Body,
+{
+    type Output = Result<http::Response<FastraceClientBody<ResBody>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        let result = ready!(this.inner.poll(cx));
+        let span = this.span.take();
+
+        if let Some(span) = &span {
+            match &result {
+                Ok(response) => {
+                    span.add_property(|| {
+                        (
+                            "http.response.status_code",
+                            response.status().as_u16().to_string(),
+                        )
+                    });
+                    record_client_status(span, response.headers());
+                }
+                Err(_) => {
+                    span.add_property(|| ("error", "true"));
+                    span.add_event(
+                        Event::new("error").with_property(|| ("error.type", "transport")),
+                    );
+                }
+            }
+        }
+
+        Poll::Ready(result.map(|response| response.map(|body| FastraceClientBody::new(body, span))))
+    }
+}
+
+impl ClientConfig {
+    /// Returns whether the trace context is injected into `req`.
+    pub(crate) fn is_enabled_for(&self, parts: &http::request::Parts) -> bool {
+        if self
+            .toggle
+            .as_ref()
+            .is_some_and(|toggle| !toggle.is_enabled())
+        {
+            return false;
+        }
+        match &self.filter {
+            Some(filter) => filter(parts),
+            None => true,
+        }
+    }
+
+    /// Injects the trace context and the forwarded headers into `req`, returning the client span
+    /// created when `client_span` is set.
+    pub(crate) fn trace<Body>(
+        &self,
+        req: Request<Body>,
+        client_span: bool,
+    ) -> (Request<Body>, Option<Span>) {
+        let (parts, body) = req.into_parts();
+        if !self.is_enabled_for(&parts) {
+            return (Request::from_parts(parts, body), None);
+        }
+        let explicit_parent = parts.extensions.get::<SpanContext>().copied();
+        let local_parent = match explicit_parent {
+            Some(_) => None,
+            None => SpanContext::current_local_parent(),
+        };
+        let parent = match explicit_parent.or(local_parent) {
+            Some(parent) => Some(parent),
+            // A trace context forwarded from the request being handled is passed through as is.
+            None if Carrier::current_carries_context() => None,
+            None => self.missing_parent_context(&parts),
+        };
+        let mut req = Request::from_parts(parts, body);
+
+        let span = client_span.then(|| {
+            let name = req.uri().path().to_owned();
+            let span = match (local_parent, parent) {
+                (Some(_), _) => Span::enter_with_local_parent(name),
+                (None, Some(parent)) => Span::root(name, parent),
+                (None, None) => Span::noop(),
+            };
+            span.add_property(|| ("span.kind", "client"));
+            span
+        });
+        let parent = span.as_ref().and_then(SpanContext::from_span).or(parent);
+        if let Some(parent) = parent {
+            let headers = req.headers();
+            if !self.keeps_existing_context(|name| headers.contains_key(name)) {
+                self.inject(&parent, req.headers_mut());
+            }
+        }
+
+        let rules =
+            self.forwarding_rules_for(req.uri().authority().map(|authority| authority.as_str()));
+        let headers = req.headers_mut();
+        Carrier::for_each_current(|name, value| {
+            if headers.contains_key(name) {
+                return;
+            }
+            let value = match rules {
+                Some(rules) => rules.filter(name, value),
+                None => Some(value.clone()),
+            };
+            if let Some(value) = value {
+                headers.insert(name.clone(), value);
+            }
+        });
+
+        (req, span)
+    }
+
+    /// Returns the span context to inject into a request made without a local parent.
+    pub(crate) fn missing_parent_context(
+        &self,
+        parts: &http::request::Parts,
+    ) -> Option<SpanContext> {
+        if let Some(supplier) = &self.missing_parent_context {
+            return supplier(parts);
+        }
+        match self.missing_parent_policy {
+            MissingParentPolicy::Skip => None,
+            MissingParentPolicy::NewTrace => Some(SpanContext::random()),
+        }
+    }
+
+    /// Returns whether the span context is not injected because the request already carries one,
+    /// as reported by `contains`.
+    pub(crate) fn keeps_existing_context(&self, contains: impl Fn(&str) -> bool) -> bool {
+        if self.existing_context_policy == ExistingContextPolicy::Overwrite {
+            return false;
+        }
+        let existing = self
+            .injected_headers
+            .iter()
+            .any(|name| contains(name.as_str()));
+        if existing && self.existing_context_policy == ExistingContextPolicy::Report {
+            LocalSpan::add_event(Event::new("existing trace context kept"));
+        }
+        existing
+    }
+
+    /// Like [`propagation::inject`], honoring the configured injector and traceparent header.
+    pub(crate) fn inject(&self, span_context: &SpanContext, headers: &mut http::HeaderMap) {
+        if let Some(injector) = &self.span_context_injector {
+            injector(span_context, headers);
+            return;
+        }
+        propagation::inject_with(span_context, |name, value| {
+            if let Ok(value) = HeaderValue::try_from(value) {
+                headers.insert(self.header_name(name), value);
+            }
+        });
+    }
+
+    /// Returns the header written for the propagation header `name`.
+    fn header_name(&self, name: &'static str) -> HeaderName {
+        match &self.traceparent_header {
+            Some(header) if name == TRACEPARENT_HEADER => header.clone(),
+            _ => HeaderName::from_static(name),
+        }
+    }
+
+    /// Returns the forwarding rules for a request to `authority`.
+    pub(crate) fn forwarding_rules_for(&self, authority: Option<&str>) -> Option<&ForwardingRules> {
+        let rules = authority.and_then(|authority| {
+            let host = authority
+                .rsplit_once(':')
+                .map_or(authority, |(host, _)| host);
+            self.forwarding_rules
+                .iter()
+                .find(|(pattern, _)| pattern == authority || pattern == host)
+                .map(|(_, rules)| rules)
+        });
+        rules.or(self.default_forwarding_rules.as_ref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future::Ready;
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::testing;
+
+    const UPSTREAM_TRACEPARENT: &str = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+
+    /// Records the headers of the last request.
+    #[derive(Clone, Default)]
+    struct Capture {
+        headers: Arc<Mutex<Option<http::HeaderMap>>>,
+    }
+
+    impl Service<Request<()>> for Capture {
+        type Response = http::Response<String>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<()>) -> Self::Future {
+            *self.headers.lock().unwrap() = Some(req.headers().clone());
+            std::future::ready(Ok(http::Response::new(String::new())))
+        }
+    }
+
+    fn send(layer: &FastraceClientLayer) -> http::HeaderMap {
+        send_with(layer, &[])
+    }
+
+    fn send_with(layer: &FastraceClientLayer, headers: &[(&str, &'static str)]) -> http::HeaderMap {
+        let mut req = Request::new(());
+        for (name, value) in headers {
+            req.headers_mut().insert(
+                HeaderName::try_from(*name).unwrap(),
+                HeaderValue::from_static(*value),
+            );
+        }
+        let capture = Capture::default();
+        drop(layer.layer(capture.clone()).call(req));
+        let headers = capture.headers.lock().unwrap().take();
+        headers.unwrap()
+    }
+
+    /// Installs the carrier of a request forwarding the upstream trace context.
+    fn forward_upstream_context() -> crate::carrier::CarrierGuard {
+        let mut carrier = Carrier::default();
+        carrier.push(
+            HeaderName::from_static(TRACEPARENT_HEADER),
+            HeaderValue::from_static(UPSTREAM_TRACEPARENT),
+        );
+        Arc::new(carrier).set_current()
+    }
+
+    #[test]
+    fn forwarded_context_takes_precedence_over_new_trace() {
+        let layer = ClientTracing::new()
+            .with_missing_parent_policy(MissingParentPolicy::NewTrace)
+            .build();
+
+        let _guard = forward_upstream_context();
+        let headers = send(&layer);
+        assert_eq!(headers[TRACEPARENT_HEADER], UPSTREAM_TRACEPARENT);
+    }
+
+    #[test]
+    fn forwarded_context_takes_precedence_over_injector() {
+        let layer = ClientTracing::new()
+            .with_missing_parent_policy(MissingParentPolicy::NewTrace)
+            .with_span_context_injector(
+                [HeaderName::from_static(TRACEPARENT_HEADER)],
+                |span_context, headers| {
+                    let traceparent = span_context.encode_w3c_traceparent();
+                    headers.insert(TRACEPARENT_HEADER, traceparent.parse().unwrap());
+                },
+            )
+            .build();
+
+        let _guard = forward_upstream_context();
+        let headers = send(&layer);
+        assert_eq!(headers[TRACEPARENT_HEADER], UPSTREAM_TRACEPARENT);
+    }
+
+    #[test]
+    fn new_trace_without_forwarded_context() {
+        let layer = ClientTracing::new()
+            .with_missing_parent_policy(MissingParentPolicy::NewTrace)
+            .build();
+
+        let headers = send(&layer);
+        assert!(propagation::extract(&headers).is_some());
+    }
+
+    #[test]
+    fn skip_without_local_parent() {
+        let headers = send(&FastraceClientLayer::default());
+        assert!(propagation::extract(&headers).is_none());
+    }
+
+    #[test]
+    fn keeps_context_in_configured_traceparent_header() {
+        let layer = ClientTracing::new()
+            .with_missing_parent_policy(MissingParentPolicy::NewTrace)
+            .with_traceparent_header(HeaderName::from_static("x-traceparent"))
+            .with_existing_context_policy(ExistingContextPolicy::KeepExisting)
+            .build();
+
+        let headers = send_with(&layer, &[("x-traceparent", UPSTREAM_TRACEPARENT)]);
+        assert_eq!(headers["x-traceparent"], UPSTREAM_TRACEPARENT);
+
+        // The layer never writes `traceparent`, so it is not a context the layer would replace.
+        if cfg!(feature = "w3c") || !cfg!(feature = "b3") {
+            let headers = send_with(&layer, &[(TRACEPARENT_HEADER, UPSTREAM_TRACEPARENT)]);
+            assert!(headers.contains_key("x-traceparent"));
+        }
+    }
+
+    #[test]
+    fn tracestate_alone_is_not_an_existing_context() {
+        let layer = ClientTracing::new()
+            .with_missing_parent_policy(MissingParentPolicy::NewTrace)
+            .with_existing_context_policy(ExistingContextPolicy::KeepExisting)
+            .build();
+
+        let headers = send_with(&layer, &[(crate::TRACESTATE_HEADER, "vendor=value")]);
+        assert!(propagation::extract(&headers).is_some());
+
+        let headers = send_with(&layer, &[(TRACEPARENT_HEADER, UPSTREAM_TRACEPARENT)]);
+        assert_eq!(headers[TRACEPARENT_HEADER], UPSTREAM_TRACEPARENT);
+    }
+
+    #[test]
+    fn keeps_context_written_by_injector() {
+        let layer = ClientTracing::new()
+            .with_missing_parent_policy(MissingParentPolicy::NewTrace)
+            .with_span_context_injector(
+                [HeaderName::from_static("x-trace-id")],
+                |span_context, headers| {
+                    let trace_id = format!("{:032x}", span_context.trace_id.0);
+                    headers.insert("x-trace-id", trace_id.parse().unwrap());
+                },
+            )
+            .with_existing_context_policy(ExistingContextPolicy::KeepExisting)
+            .build();
+
+        let headers = send_with(&layer, &[("x-trace-id", "existing")]);
+        assert_eq!(headers["x-trace-id"], "existing");
+
+        let headers = send_with(&layer, &[(TRACEPARENT_HEADER, UPSTREAM_TRACEPARENT)]);
+        assert_ne!(headers["x-trace-id"], "existing");
+        assert!(headers.contains_key("x-trace-id"));
+    }
+
+    #[test]
+    fn client_span_parents_the_injected_context() {
+        testing::init();
+        let layer = ClientTracing::new()
+            .with_missing_parent_policy(MissingParentPolicy::NewTrace)
+            .build_with_client_span();
+
+        let capture = Capture::default();
+        let response = testing::block_on(layer.layer(capture.clone()).call(Request::new(())));
+        drop(response);
+
+        let headers = capture.headers.lock().unwrap().take().unwrap();
+        let context = propagation::extract(&headers).unwrap();
+        let spans = testing::spans(context.trace_id);
+        let span = testing::span(&spans, "/");
+        assert_eq!(span.span_id, context.span_id);
+        assert_eq!(testing::property(span, "span.kind"), Some("client"));
+        assert_eq!(
+            testing::property(span, "http.response.status_code"),
+            Some("200")
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use std::time::Instant;
+
+use bytes::Buf;
+use bytes::BufMut;
+use fastrace::prelude::*;
+use tonic::Status;
+use tonic::codec::BufferSettings;
+use tonic::codec::Codec;
+use tonic::codec::DecodeBuf;
+use tonic::codec::Decoder;
+use tonic::codec::EncodeBuf;
+use tonic::codec::Encoder;
+
+/// A tonic [`Codec`] wrapper recording the time spent encoding and decoding messages.
+///
+/// Every message encoded or decoded adds a `message encoded` or `message decoded` event to the
+/// current local parent, with the `duration_us` property holding the time spent in the wrapped
+/// codec, in microseconds, and the `message.size` property holding the encoded size, in bytes.
+/// Responses are encoded while the response body is polled and requests decoded while the
+/// handler runs, so with [`FastraceServerLayer`](crate::FastraceServerLayer) both land on the
+/// request span.
+///
+/// Generated services pick their codec through `tonic_build`'s `codec_path`:
+///
+/// ```ignore
+/// tonic_build::configure()
+///     .codec_path("fastrace_tonic::FastraceCodec::<tonic::codec::ProstCodec<_, _>>")
+///     .compile_protos(&["proto/greeter.proto"], &["proto"])?;
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FastraceCodec<C> {
+    inner: C,
+}
+
+impl<C> FastraceCodec<C> {
+    /// Wraps a codec.
+    pub fn new(inner: C) -> Self {
+        Self { inner }
+    }
+}
+
+impl<C: Codec> Codec for FastraceCodec<C> {
+    type Encode = C::Encode;
+    type Decode = C::Decode;
+    type Encoder = FastraceEncoder<C::Encoder>;
+    type Decoder = FastraceDecoder<C::Decoder>;
+
+    fn encoder(&mut self) -> Self::Encoder {
+        FastraceEncoder {
+            inner: self.inner.encoder(),
+        }
+    }
+
+    fn decoder(&mut self) -> Self::Decoder {
+        FastraceDecoder {
+            inner: self.inner.decoder(),
+        }
+    }
+}
+
+/// The encoder of a [`FastraceCodec`].
+#[derive(Debug)]
+pub struct FastraceEncoder<E> {
+    inner: E,
+}
+
+impl<E: Encoder<Error = Status>> Encoder for FastraceEncoder<E> {
+    type Item = E::Item;
+    type Error = Status;
+
+    fn encode(&mut self, item: Self::Item, dst: &mut EncodeBuf<'_>) -> Result<(), Self::Error> {
+        let start = Instant::now();
+        // The buffer only grows while encoding, shrinking the remaining capacity by the size of
+        // the message.
+        let remaining = dst.remaining_mut();
+        let result = self.inner.encode(item, dst);
+        add_event("message encoded", start, remaining - dst.remaining_mut());
+        result
+    }
+
+    fn buffer_settings(&self) -> BufferSettings {
+        self.inner.buffer_settings()
+    }
+}
+
+/// The decoder of a [`FastraceCodec`].
+#[derive(Debug)]
+pub struct FastraceDecoder<D> {
+    inner: D,
+}
+
+impl<D: Decoder<Error = Status>> Decoder for FastraceDecoder<D> {
+    type Item = D::Item;
+    type Error = Status;
+
+    fn decode(&mut self, src: &mut DecodeBuf<'_>) -> Result<Option<Self::Item>, Self::Error> {
+        let start = Instant::now();
+        let size = src.remaining();
+        let result = self.inner.decode(src);
+        add_event("message decoded", start, size);
+        result
+    }
+
+    fn buffer_settings(&self) -> BufferSettings {
+        self.inner.buffer_settings()
+    }
+}
+
+fn add_event(name: &'static str, start: Instant, size: usize) {
+    let duration = start.elapsed();
+    LocalSpan::add_event(Event::new(name).with_properties(|| {
+        [
+            ("duration_us", duration.as_micros().to_string()),
+            ("message.size", size.to_string()),
+        ]
+    }));
+}
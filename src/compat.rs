@@ -0,0 +1,253 @@
+//! Layer support for stacks built on `http` 0.2, such as tonic 0.11.
+//!
+//! With the `compat-http02` feature, [`FastraceServerService`] and [`FastraceClientService`]
+//! also implement `Service` for `http` 0.2 requests, so one version of this crate can instrument
+//! services on both sides of an `http` upgrade. The request head is converted to `http` 1 types
+//! before running the server configuration, so request extensions are not visible to filters
+//! and extractors, and only the [`CacheStatus`] of the response is recorded.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::task::ready;
+
+use fastrace::prelude::*;
+use tower_service::Service;
+
+use crate::CacheStatus;
+use crate::FastraceClientService;
+use crate::FastraceServerService;
+use crate::RequestSpan;
+use crate::carrier::Carrier;
+
+impl<S, ReqBody, ResBody> Service<http02::Request<ReqBody>> for FastraceServerService<S>
+where S: Service<http02::Request<ReqBody>, Response = http02::Response<ResBody>>
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = FastraceServerFuture02<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http02::Request<ReqBody>) -> Self::Future {
+        let mut parts = convert_parts(&req);
+        let config = self.config.for_request(&parts);
+        if config.is_excluded(&parts) {
+            return FastraceServerFuture02 {
+                inner: self.service.call(req),
+                span: None,
+                carrier: None,
+            };
+        }
+        // The id is generated on the converted head, which the inner service never sees.
+        if let Some((header, id)) = config.ensure_request_id(&mut parts.headers) {
+            if let (Ok(header), Ok(id)) = (
+                http02::HeaderName::from_bytes(header.as_str().as_bytes()),
+                http02::HeaderValue::from_bytes(id.as_bytes()),
+            ) {
+                req.headers_mut().insert(header, id);
+            }
+        }
+        let span = RequestSpan::new(config.make_span(&parts));
+        let carrier = config.make_carrier(&parts);
+        req.extensions_mut().insert(span.clone());
+        FastraceServerFuture02 {
+            inner: self.service.call(req),
+            span: Some(span),
+            carrier,
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Response future of [`FastraceServerService`] for `http` 0.2 requests.
+    pub struct FastraceServerFuture02<F> {
+        #[pin]
+        inner: F,
+        span: Option<RequestSpan>,
+        carrier: Option<Arc<Carrier>>,
+    }
+}
+
+impl<F, ResBody, E> Future for FastraceServerFuture02<F>
+where F: Future<Output = Result<http02::Response<ResBody>, E>>
+{
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        let _guard = this.span.as_ref().map(|span| span.set_local_parent());
+        let _carrier_guard = this.carrier.as_ref().map(|carrier| carrier.set_current());
+        let result = ready!(this.inner.poll(cx));
+
+        if let Some(span) = this.span.take() {
+            if let Ok(response) = &result {
+                if let Some(status) = response.extensions().get::<CacheStatus>() {
+                    span.add_property(|| ("cache.status", status.as_str()));
+                }
+            }
+        }
+
+        Poll::Ready(result)
+    }
+}
+
+impl<S, Body> Service<http02::Request<Body>> for FastraceClientService<S>
+where S: Service<http02::Request<Body>>
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: http02::Request<Body>) -> Self::Future {
+        let parts = convert_parts(&req);
+        if !self.config.is_enabled_for(&parts) {
+            return self.service.call(req);
+        }
+
+        let rules = self
+            .config
+            .forwarding_rules_for(req.uri().authority().map(|authority| authority.as_str()));
+        let parent = match req
+            .extensions()
+            .get::<SpanContext>()
+            .copied()
+            .or_else(SpanContext::current_local_parent)
+        {
+            Some(parent) => Some(parent),
+            None if Carrier::current_carries_context() => None,
+            None => self.config.missing_parent_context(&parts),
+        };
+        let headers = req.headers_mut();
+        if let Some(current) = parent {
+            if !self
+                .config
+                .keeps_existing_context(|name| headers.contains_key(name))
+            {
+                let mut injected = http::HeaderMap::new();
+                self.config.inject(&current, &mut injected);
+                for (name, value) in &injected {
+                    if let (Ok(name), Ok(value)) = (
+                        http02::HeaderName::from_bytes(name.as_str().as_bytes()),
+                        http02::HeaderValue::from_bytes(value.as_bytes()),
+                    ) {
+                        headers.insert(name, value);
+                    }
+                }
+            }
+        }
+        Carrier::for_each_current(|name, value| {
+            let value = match rules {
+                Some(rules) => rules.filter(name, value),
+                None => Some(value.clone()),
+            };
+            if let (Ok(name), Some(Ok(value))) = (
+                http02::HeaderName::from_bytes(name.as_str().as_bytes()),
+                value.map(|value| http02::HeaderValue::from_bytes(value.as_bytes())),
+            ) {
+                headers.entry(name).or_insert(value);
+            }
+        });
+
+        self.service.call(req)
+    }
+}
+
+/// Converts the head of an `http` 0.2 request, skipping anything that does not convert cleanly.
+fn convert_parts<B>(req: &http02::Request<B>) -> http::request::Parts {
+    let mut builder = http::Request::builder();
+    if let Ok(method) = http::Method::from_bytes(req.method().as_str().as_bytes()) {
+        builder = builder.method(method);
+    }
+    if let Ok(uri) = req.uri().to_string().parse::<http::Uri>() {
+        builder = builder.uri(uri);
+    }
+    if let Some(headers) = builder.headers_mut() {
+        for (name, value) in req.headers() {
+            if let (Ok(name), Ok(value)) = (
+                http::HeaderName::from_bytes(name.as_str().as_bytes()),
+                http::HeaderValue::from_bytes(value.as_bytes()),
+            ) {
+                headers.append(name, value);
+            }
+        }
+    }
+
+    let (parts, ()) = builder.body(()).unwrap_or_default().into_parts();
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future::Ready;
+    use std::sync::Mutex;
+
+    use tower_layer::Layer;
+
+    use super::*;
+    use crate::ServerTracing;
+
+    /// Records the headers of the last request.
+    #[derive(Clone, Default)]
+    struct Capture {
+        headers: Arc<Mutex<Option<http02::HeaderMap>>>,
+    }
+
+    impl Service<http02::Request<()>> for Capture {
+        type Response = http02::Response<()>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: http02::Request<()>) -> Self::Future {
+            *self.headers.lock().unwrap() = Some(req.headers().clone());
+            std::future::ready(Ok(http02::Response::new(())))
+        }
+    }
+
+    fn send(request: http02::Request<()>) -> http02::HeaderMap {
+        let layer = ServerTracing::new()
+            .with_request_id(http::HeaderName::from_static("x-request-id"))
+            .build();
+        let capture = Capture::default();
+        drop(layer.layer(capture.clone()).call(request));
+        let headers = capture.headers.lock().unwrap().take();
+        headers.unwrap()
+    }
+
+    #[test]
+    fn generated_request_id_is_passed_to_the_inner_service() {
+        let request = http02::Request::builder()
+            .uri("/helloworld.Greeter/SayHello")
+            .body(())
+            .unwrap();
+
+        let headers = send(request);
+        assert_eq!(headers["x-request-id"].len(), 32);
+    }
+
+    #[test]
+    fn existing_request_id_is_kept() {
+        let request = http02::Request::builder()
+            .uri("/helloworld.Greeter/SayHello")
+            .header("x-request-id", "existing")
+            .body(())
+            .unwrap();
+
+        let headers = send(request);
+        assert_eq!(headers["x-request-id"], "existing");
+    }
+}
@@ -0,0 +1,448 @@
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::future::Future;
+use std::net::IpAddr;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::task::Context;
+use std::task::Poll;
+use std::task::ready;
+
+use fastrace::prelude::*;
+use http::Uri;
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Client connector layer recording where connections were actually established.
+///
+/// Wrap the connector passed to tonic's `Endpoint::connect_with_connector` to create a `connect`
+/// span for every new connection, as a child of the current local parent or as a new trace.
+/// The requested authority is recorded as the `server.address` and `server.port` properties
+/// and, once connected, the address returned by `connected_addr` for the connection as
+/// `network.peer.address` and `network.peer.port`. This shows which backend a DNS round-robin
+/// actually picked.
+///
+/// The connector is called and polled with the `connect` span as the local parent, so a resolver
+/// wrapped with [`FastraceResolver`] records DNS resolution as a child span, leaving the rest of
+/// the `connect` span to the TCP connection. The addresses it resolves are also handed to the
+/// `connect` span: when the connected address is neither the requested host, for IP addresses,
+/// nor one of the addresses resolved for the connection, a `connected address mismatch` event is
+/// added, such as when a proxy or a stale resolution picked the backend.
+///
+/// ```
+/// use fastrace_tonic::FastraceConnectLayer;
+///
+/// struct Connection {
+///     peer: std::net::SocketAddr,
+/// }
+///
+/// let layer = FastraceConnectLayer::new(|connection: &Connection| Some(connection.peer));
+/// ```
+pub struct FastraceConnectLayer<F> {
+    connected_addr: Arc<F>,
+}
+
+impl<F> FastraceConnectLayer<F> {
+    /// Creates a layer reading the connected address of a connection with `connected_addr`.
+    pub fn new(connected_addr: F) -> Self {
+        Self {
+            connected_addr: Arc::new(connected_addr),
+        }
+    }
+}
+
+impl<F> Clone for FastraceConnectLayer<F> {
+    fn clone(&self) -> Self {
+        Self {
+            connected_addr: self.connected_addr.clone(),
+        }
+    }
+}
+
+impl<C, F> Layer<C> for FastraceConnectLayer<F> {
+    type Service = FastraceConnectService<C, F>;
+
+    fn layer(&self, connector: C) -> Self::Service {
+        FastraceConnectService {
+            connector,
+            connected_addr: self.connected_addr.clone(),
+        }
+    }
+}
+
+/// Client connector recording where connections were actually established.
+///
+/// See [`FastraceConnectLayer`] for details.
+pub struct FastraceConnectService<C, F> {
+    connector: C,
+    connected_addr: Arc<F>,
+}
+
+impl<C: Clone, F> Clone for FastraceConnectService<C, F> {
+    fn clone(&self) -> Self {
+        Self {
+            connector: self.connector.clone(),
+            connected_addr: self.connected_addr.clone(),
+        }
+    }
+}
+
+impl<C, F> Service<Uri> for FastraceConnectService<C, F>
+where
+    C: Service<Uri>,
+    F: Fn(&C::Response) -> Option<SocketAddr>,
+{
+    type Response = C::Response;
+    type Error = C::Error;
+    type Future = FastraceConnectFuture<C::Future, F>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.connector.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let span = match SpanContext::current_local_parent() {
+            Some(_) => Span::enter_with_local_parent("connect"),
+            None => Span::root("connect", SpanContext::random()),
+        };
+        let resolved = Arc::new(Mutex::new(Vec::new()));
+        if let Some(ip) = uri.host().and_then(|host| {
+            host.trim_start_matches('[')
+                .trim_end_matches(']')
+                .parse::<IpAddr>()
+                .ok()
+        }) {
+            resolved
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .push(ip);
+        }
+        if let Some(host) = uri.host() {
+            span.add_property(|| ("server.address", host.to_owned()));
+        }
+        if let Some(port) = uri.port_u16() {
+            span.add_property(|| ("server.port", port.to_string()));
+        }
+
+        let inner = {
+            let _guard = span.set_local_parent();
+            let _resolving = ResolveScope::enter(&resolved);
+            self.connector.call(uri)
+        };
+        FastraceConnectFuture {
+            inner,
+            span: Some(span),
+            resolved,
+            connected_addr: self.connected_addr.clone(),
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Response future of [`FastraceConnectService`].
+    pub struct FastraceConnectFuture<Fut, F> {
+        #[pin]
+        inner: Fut,
+        span: Option<Span>,
+        resolved: ResolvedAddrs,
+        connected_addr: Arc<F>,
+    }
+}
+
+impl<Fut, F, T, E> Future for FastraceConnectFuture<Fut, F>
+where
+    Fut: Future<Output = Result<T, E>>,
+    F: Fn(&T) -> Option<SocketAddr>,
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        let result = {
+            let _guard = this.span.as_ref().map(|span| span.set_local_parent());
+            let _resolving = ResolveScope::enter(this.resolved);
+            ready!(this.inner.poll(cx))
+        };
+
+        if let Some(span) = this.span.take() {
+            match &result {
+                Ok(connection) => {
+                    if let Some(addr) = (this.connected_addr)(connection) {
+                        span.add_properties(|| {
+                            [
+                                ("network.peer.address", addr.ip().to_string()),
+                                ("network.peer.port", addr.port().to_string()),
+                            ]
+                        });
+                        let resolved = this.resolved.lock().unwrap_or_else(PoisonError::into_inner);
+                        if !resolved.is_empty() && !resolved.contains(&addr.ip()) {
+                            span.add_event(
+                                Event::new("connected address mismatch").with_properties(|| {
+                                    [
+                                        ("requested", join_ips(&resolved)),
+                                        ("connected", addr.ip().to_string()),
+                                    ]
+                                }),
+                            );
+                        }
+                    }
+                }
+                Err(_) => span.add_event(Event::new("connect failed")),
+            }
+        }
+
+        Poll::Ready(result)
+    }
+}
+
+/// Resolver wrapper creating spans for DNS resolution.
+///
+/// Wrap the resolver of a connector, such as the one given to hyper-util's
+/// `HttpConnector::new_with_resolver`, to create a `dns resolve` span for every resolution, as a
+/// child of the current local parent or as a new trace. Together with [`FastraceConnectLayer`],
+/// it becomes a child of the `connect` span. The resolved name is recorded as the
+/// `server.address` property and, once resolved, the addresses as the comma-separated
+/// `dns.addresses` property. A failed resolution adds a `dns resolve failed` event.
+#[derive(Clone)]
+pub struct FastraceResolver<R> {
+    resolver: R,
+}
+
+impl<R> FastraceResolver<R> {
+    /// Wraps `resolver`.
+    pub fn new(resolver: R) -> Self {
+        Self { resolver }
+    }
+}
+
+impl<R, N> Service<N> for FastraceResolver<R>
+where
+    R: Service<N>,
+    R::Response: IntoIterator<Item = SocketAddr>,
+    N: Display,
+{
+    type Response = std::vec::IntoIter<SocketAddr>;
+    type Error = R::Error;
+    type Future = FastraceResolveFuture<R::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.resolver.poll_ready(cx)
+    }
+
+    fn call(&mut self, name: N) -> Self::Future {
+        let span = match SpanContext::current_local_parent() {
+            Some(_) => Span::enter_with_local_parent("dns resolve"),
+            None => Span::root("dns resolve", SpanContext::random()),
+        };
+        span.add_property(|| ("server.address", name.to_string()));
+
+        FastraceResolveFuture {
+            inner: self.resolver.call(name),
+            span: Some(span),
+            resolved: ResolveScope::current(),
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Response future of [`FastraceResolver`].
+    pub struct FastraceResolveFuture<Fut> {
+        #[pin]
+        inner: Fut,
+        span: Option<Span>,
+        resolved: Option<ResolvedAddrs>,
+    }
+}
+
+impl<Fut, A, E> Future for FastraceResolveFuture<Fut>
+where
+    Fut: Future<Output = Result<A, E>>,
+    A: IntoIterator<Item = SocketAddr>,
+{
+    type Output = Result<std::vec::IntoIter<SocketAddr>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        let result = ready!(this.inner.poll(cx)).map(|addrs| addrs.into_iter().collect::<Vec<_>>());
+
+        if let (Ok(addrs), Some(resolved)) = (&result, this.resolved.take()) {
+            let mut resolved = resolved.lock().unwrap_or_else(PoisonError::into_inner);
+            resolved.extend(addrs.iter().map(SocketAddr::ip));
+        }
+        if let Some(span) = this.span.take() {
+            match &result {
+                Ok(addrs) => span.add_property(|| {
+                    let addrs: Vec<IpAddr> = addrs.iter().map(SocketAddr::ip).collect();
+                    ("dns.addresses", join_ips(&addrs))
+                }),
+                Err(_) => {
+                    span.add_property(|| ("error", "true"));
+                    span.add_event(Event::new("dns resolve failed"));
+                }
+            }
+        }
+
+        Poll::Ready(result.map(Vec::into_iter))
+    }
+}
+
+/// The addresses a connection may legitimately end up at, shared between a
+/// [`FastraceConnectFuture`] and the [`FastraceResolveFuture`]s of its connector.
+type ResolvedAddrs = Arc<Mutex<Vec<IpAddr>>>;
+
+thread_local! {
+    static RESOLVED: RefCell<Option<ResolvedAddrs>> = const { RefCell::new(None) };
+}
+
+/// Makes resolutions started while the connector is called or polled report to a connection.
+struct ResolveScope {
+    previous: Option<ResolvedAddrs>,
+}
+
+impl ResolveScope {
+    fn enter(resolved: &ResolvedAddrs) -> Self {
+        let previous = RESOLVED.with(|current| current.replace(Some(resolved.clone())));
+        Self { previous }
+    }
+
+    fn current() -> Option<ResolvedAddrs> {
+        RESOLVED.with(|current| current.borrow().clone())
+    }
+}
+
+impl Drop for ResolveScope {
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        RESOLVED.with(|current| *current.borrow_mut() = previous);
+    }
+}
+
+fn join_ips(ips: &[IpAddr]) -> String {
+    let ips: Vec<String> = ips.iter().map(IpAddr::to_string).collect();
+    ips.join(",")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future::Ready;
+
+    use super::*;
+    use crate::testing;
+
+    struct Connection {
+        peer: SocketAddr,
+    }
+
+    #[derive(Clone)]
+    struct StaticResolver(Vec<SocketAddr>);
+
+    impl Service<String> for StaticResolver {
+        type Response = Vec<SocketAddr>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: String) -> Self::Future {
+            std::future::ready(Ok(self.0.clone()))
+        }
+    }
+
+    /// Resolves the host of the URI, then "connects" to `peer`.
+    struct Connector {
+        resolver: FastraceResolver<StaticResolver>,
+        peer: SocketAddr,
+    }
+
+    impl Service<Uri> for Connector {
+        type Response = Connection;
+        type Error = Infallible;
+        type Future = Pin<Box<dyn Future<Output = Result<Connection, Infallible>>>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, uri: Uri) -> Self::Future {
+            let resolve = self
+                .resolver
+                .call(uri.host().unwrap_or_default().to_owned());
+            let peer = self.peer;
+            Box::pin(async move {
+                resolve.await?;
+                Ok(Connection { peer })
+            })
+        }
+    }
+
+    fn connect(uri: &str, resolved: &[&str], peer: &str) -> SpanRecord {
+        testing::init();
+        let resolved = resolved.iter().map(|addr| addr.parse().unwrap()).collect();
+        let connector = Connector {
+            resolver: FastraceResolver::new(StaticResolver(resolved)),
+            peer: peer.parse().unwrap(),
+        };
+        let mut service =
+            FastraceConnectLayer::new(|connection: &Connection| Some(connection.peer))
+                .layer(connector);
+
+        let parent = SpanContext::random();
+        {
+            let root = Span::root("test", parent);
+            let _guard = root.set_local_parent();
+            testing::block_on(service.call(uri.parse().unwrap())).unwrap();
+        }
+        testing::span(&testing::spans(parent.trace_id), "connect").clone()
+    }
+
+    #[test]
+    fn hostname_connected_outside_resolved_addresses() {
+        let span = connect(
+            "http://backend.internal:50051",
+            &["10.0.0.1:50051", "10.0.0.2:50051"],
+            "10.0.0.3:50051",
+        );
+        let event = span
+            .events
+            .iter()
+            .find(|event| event.name == "connected address mismatch")
+            .expect("mismatch event");
+        let properties: Vec<_> = event
+            .properties
+            .iter()
+            .map(|(key, value)| (key.as_ref(), value.as_ref()))
+            .collect();
+        assert_eq!(properties, [
+            ("requested", "10.0.0.1,10.0.0.2"),
+            ("connected", "10.0.0.3")
+        ]);
+    }
+
+    #[test]
+    fn hostname_connected_to_resolved_address() {
+        let span = connect(
+            "http://backend.internal:50051",
+            &["10.0.0.1:50051", "10.0.0.2:50051"],
+            "10.0.0.2:50051",
+        );
+        assert_eq!(
+            testing::property(&span, "network.peer.address"),
+            Some("10.0.0.2")
+        );
+        assert!(!testing::has_event(&span, "connected address mismatch"));
+    }
+
+    #[test]
+    fn ip_literal_compared_with_connected_address() {
+        let span = connect("http://10.0.0.1:50051", &[], "10.0.0.2:50051");
+        assert!(testing::has_event(&span, "connected address mismatch"));
+    }
+}
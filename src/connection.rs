@@ -0,0 +1,225 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::task::Context;
+use std::task::Poll;
+use std::task::ready;
+
+use fastrace::prelude::*;
+use tower_service::Service;
+
+/// Make service wrapper creating a span for every accepted connection.
+///
+/// Wrap the make service handed to the server, such as hyper's or axum's, to create a
+/// `connection` root span for every connection it is called for. `record` is called with the
+/// connection target to add properties such as the peer address, and with the `transport`
+/// feature, [`record_connect_info`] does so for targets implementing tonic's `Connected`. A
+/// `connection established` event is added once the per-connection service is made, or a
+/// `connection failed` event when making it fails. Errors reported by the per-connection service
+/// add a `connection error` event. The span ends with a `connection closed` event holding the
+/// number of requests served once the server drops the per-connection service, which happens
+/// when the connection closes. This shows the HTTP/2 connection churn that request spans alone
+/// cannot.
+///
+/// ```ignore
+/// use fastrace::prelude::*;
+/// use fastrace_tonic::FastraceMakeService;
+///
+/// let make_service = FastraceMakeService::new(
+///     app.into_make_service(),
+///     |stream: &axum::serve::IncomingStream<'_>, span: &Span| {
+///         let addr = stream.remote_addr();
+///         span.add_property(|| ("network.peer.address", addr.ip().to_string()));
+///     },
+/// );
+/// axum::serve(listener, make_service).await?;
+/// ```
+pub struct FastraceMakeService<M, F> {
+    make_service: M,
+    record: Arc<F>,
+}
+
+impl<M, F> FastraceMakeService<M, F> {
+    /// Wraps `make_service`, recording the target of every connection with `record`.
+    pub fn new(make_service: M, record: F) -> Self {
+        Self {
+            make_service,
+            record: Arc::new(record),
+        }
+    }
+}
+
+impl<M: Clone, F> Clone for FastraceMakeService<M, F> {
+    fn clone(&self) -> Self {
+        Self {
+            make_service: self.make_service.clone(),
+            record: self.record.clone(),
+        }
+    }
+}
+
+impl<M, F, T> Service<T> for FastraceMakeService<M, F>
+where
+    M: Service<T>,
+    F: Fn(&T, &Span),
+{
+    type Response = FastraceConnectionService<M::Response>;
+    type Error = M::Error;
+    type Future = FastraceMakeFuture<M::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.make_service.poll_ready(cx)
+    }
+
+    fn call(&mut self, target: T) -> Self::Future {
+        let span = Span::root("connection", SpanContext::random());
+        (self.record)(&target, &span);
+
+        FastraceMakeFuture {
+            inner: self.make_service.call(target),
+            span: Some(span),
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Response future of [`FastraceMakeService`].
+    pub struct FastraceMakeFuture<Fut> {
+        #[pin]
+        inner: Fut,
+        span: Option<Span>,
+    }
+}
+
+impl<Fut, S, E> Future for FastraceMakeFuture<Fut>
+where Fut: Future<Output = Result<S, E>>
+{
+    type Output = Result<FastraceConnectionService<S>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        let result = ready!(this.inner.poll(cx));
+        let span = this.span.take().unwrap_or_else(Span::noop);
+
+        Poll::Ready(match result {
+            Ok(service) => {
+                span.add_event(Event::new("connection established"));
+                Ok(FastraceConnectionService {
+                    service,
+                    connection: Arc::new(Connection {
+                        span,
+                        requests: AtomicU64::new(0),
+                    }),
+                })
+            }
+            Err(err) => {
+                span.add_property(|| ("error", "true"));
+                span.add_event(Event::new("connection failed"));
+                Err(err)
+            }
+        })
+    }
+}
+
+/// Per-connection service made by [`FastraceMakeService`].
+///
+/// The connection span ends once this service and all its clones are dropped.
+pub struct FastraceConnectionService<S> {
+    service: S,
+    connection: Arc<Connection>,
+}
+
+impl<S: Clone> Clone for FastraceConnectionService<S> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            connection: self.connection.clone(),
+        }
+    }
+}
+
+impl<S, R> Service<R> for FastraceConnectionService<S>
+where S: Service<R>
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        let poll = self.service.poll_ready(cx);
+        if let Poll::Ready(Err(_)) = &poll {
+            self.connection
+                .span
+                .add_event(Event::new("connection error"));
+        }
+        poll
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        self.connection.requests.fetch_add(1, Ordering::Relaxed);
+        self.service.call(req)
+    }
+}
+
+struct Connection {
+    span: Span,
+    requests: AtomicU64,
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        let requests = self.requests.load(Ordering::Relaxed);
+        self.span.add_event(
+            Event::new("connection closed").with_property(|| ("requests", requests.to_string())),
+        );
+    }
+}
+
+/// A ready-made connection recorder for targets implementing tonic's `Connected`, for use with
+/// [`FastraceMakeService::new`].
+///
+/// It records the peer address as the `network.peer.address` and `network.peer.port`
+/// properties, how the connection was accepted as the `network.transport` property, either
+/// `unix` or `tcp`, with the `tls.established` property set to `true` for TLS connections, and,
+/// with the `mtls` feature, the identity of the client certificate like
+/// `ServerTracing::with_client_identity` does for requests, parsed once per connection.
+///
+/// An [`InProcessPeer`](crate::InProcessPeer) is recorded like it is for requests. When the
+/// connect info of a TCP connection holds no remote address, or the target provides connect
+/// info of another kind, a `peer address unavailable` event is added to the connection
+/// span, since [`ServerTracing::with_peer_address`](crate::ServerTracing::with_peer_address)
+/// cannot record the address of its requests either.
+#[cfg(feature = "transport")]
+pub fn record_connect_info<T>(target: &T, span: &Span)
+where T: tonic::transport::server::Connected {
+    // Looked up from extensions, like the connect info of requests.
+    let mut extensions = http::Extensions::new();
+    extensions.insert(target.connect_info());
+
+    if let Some(addr) = crate::peer::connect_info_remote_addr(&extensions) {
+        crate::peer::record_socket_addr(span, addr);
+    } else if let Some(peer) = extensions.get::<crate::InProcessPeer>() {
+        peer.record(span);
+    } else {
+        #[cfg(unix)]
+        let uds = extensions
+            .get::<tonic::transport::server::UdsConnectInfo>()
+            .is_some();
+        #[cfg(not(unix))]
+        let uds = false;
+        if !uds {
+            span.add_event(
+                Event::new("peer address unavailable")
+                    .with_property(|| ("reason", "connect info holds no remote address")),
+            );
+        }
+    }
+    #[cfg(unix)]
+    crate::peer::record_uds_peer(span, &extensions);
+    crate::peer::record_tcp_transport(span, &extensions);
+    #[cfg(feature = "mtls")]
+    crate::peer::record_peer_identity(span, &extensions);
+}
@@ -0,0 +1,213 @@
+//! Decoding of the `google.rpc.Status` carried by `grpc-status-details-bin`.
+
+use fastrace::prelude::*;
+use prost::Message;
+
+const RETRY_INFO_TYPE_URL: &str = "type.googleapis.com/google.rpc.RetryInfo";
+const BAD_REQUEST_TYPE_URL: &str = "type.googleapis.com/google.rpc.BadRequest";
+const ERROR_INFO_TYPE_URL: &str = "type.googleapis.com/google.rpc.ErrorInfo";
+
+#[derive(Clone, PartialEq, Message)]
+struct Status {
+    #[prost(int32, tag = "1")]
+    code: i32,
+    #[prost(string, tag = "2")]
+    message: String,
+    #[prost(message, repeated, tag = "3")]
+    details: Vec<prost_types::Any>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct RetryInfo {
+    #[prost(message, optional, tag = "1")]
+    retry_delay: Option<prost_types::Duration>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct BadRequest {
+    #[prost(message, repeated, tag = "1")]
+    field_violations: Vec<FieldViolation>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct FieldViolation {
+    #[prost(string, tag = "1")]
+    field: String,
+    #[prost(string, tag = "2")]
+    description: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct ErrorInfo {
+    #[prost(string, tag = "1")]
+    reason: String,
+    #[prost(string, tag = "2")]
+    domain: String,
+}
+
+/// Adds an event for each detail of an encoded `google.rpc.Status`.
+///
+/// `RetryInfo`, `BadRequest` and `ErrorInfo` details are decoded into `retry info`,
+/// `field violation` and `error info` events. Other details get a `status detail` event
+/// holding their type URL.
+pub(crate) fn record_details(span: &Span, status: &[u8]) {
+    let Ok(status) = Status::decode(status) else {
+        return;
+    };
+    for detail in status.details {
+        match detail.type_url.as_str() {
+            RETRY_INFO_TYPE_URL => {
+                let Ok(info) = RetryInfo::decode(detail.value.as_slice()) else {
+                    continue;
+                };
+                let delay_ms = info.retry_delay.map_or(0, |delay| {
+                    delay.seconds.saturating_mul(1000) + i64::from(delay.nanos / 1_000_000)
+                });
+                span.add_event(
+                    Event::new("retry info")
+                        .with_property(|| ("retry_delay_ms", delay_ms.to_string())),
+                );
+            }
+            BAD_REQUEST_TYPE_URL => {
+                let Ok(bad_request) = BadRequest::decode(detail.value.as_slice()) else {
+                    continue;
+                };
+                for violation in bad_request.field_violations {
+                    span.add_event(Event::new("field violation").with_properties(|| {
+                        [
+                            ("field", violation.field),
+                            ("description", violation.description),
+                        ]
+                    }));
+                }
+            }
+            ERROR_INFO_TYPE_URL => {
+                let Ok(info) = ErrorInfo::decode(detail.value.as_slice()) else {
+                    continue;
+                };
+                span.add_event(
+                    Event::new("error info")
+                        .with_properties(|| [("reason", info.reason), ("domain", info.domain)]),
+                );
+            }
+            _ => {
+                span.add_event(
+                    Event::new("status detail").with_property(|| ("type_url", detail.type_url)),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD_NO_PAD;
+    use fastrace::collector::SpanRecord;
+    use http::HeaderMap;
+    use http::HeaderValue;
+
+    use super::*;
+    use crate::StatusInfo;
+    use crate::testing;
+
+    fn any(type_url: &str, value: impl Message) -> prost_types::Any {
+        prost_types::Any {
+            type_url: type_url.to_owned(),
+            value: value.encode_to_vec(),
+        }
+    }
+
+    fn status() -> Vec<u8> {
+        Status {
+            code: 3,
+            message: "invalid argument".to_owned(),
+            details: vec![
+                any(RETRY_INFO_TYPE_URL, RetryInfo {
+                    retry_delay: Some(prost_types::Duration {
+                        seconds: 1,
+                        nanos: 500_000_000,
+                    }),
+                }),
+                any(BAD_REQUEST_TYPE_URL, BadRequest {
+                    field_violations: vec![FieldViolation {
+                        field: "name".to_owned(),
+                        description: "must not be empty".to_owned(),
+                    }],
+                }),
+                any(ERROR_INFO_TYPE_URL, ErrorInfo {
+                    reason: "QUOTA_EXCEEDED".to_owned(),
+                    domain: "example.com".to_owned(),
+                }),
+                any("type.googleapis.com/example.Custom", ErrorInfo::default()),
+            ],
+        }
+        .encode_to_vec()
+    }
+
+    /// Records the details of a response carrying `details` as its `grpc-status-details-bin`
+    /// trailer, like the server layer does.
+    fn record(details: &str) -> SpanRecord {
+        testing::init();
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "grpc-status-details-bin",
+            HeaderValue::from_str(details).unwrap(),
+        );
+        let status = StatusInfo::new(http::StatusCode::OK, &headers);
+
+        let parent = SpanContext::random();
+        {
+            let span = Span::root("request", parent);
+            if let Some(details) = status.details() {
+                record_details(&span, &details);
+            }
+        }
+        testing::span(&testing::spans(parent.trace_id), "request").clone()
+    }
+
+    fn event<'a>(span: &'a SpanRecord, name: &str) -> Vec<(&'a str, &'a str)> {
+        let event = span
+            .events
+            .iter()
+            .find(|event| event.name == name)
+            .unwrap_or_else(|| panic!("no `{name}` event in {span:?}"));
+        event
+            .properties
+            .iter()
+            .map(|(key, value)| (key.as_ref(), value.as_ref()))
+            .collect()
+    }
+
+    #[test]
+    fn details_are_recorded_as_events() {
+        let span = record(&STANDARD_NO_PAD.encode(status()));
+
+        assert_eq!(event(&span, "retry info"), [("retry_delay_ms", "1500")]);
+        assert_eq!(event(&span, "field violation"), [
+            ("field", "name"),
+            ("description", "must not be empty")
+        ]);
+        assert_eq!(event(&span, "error info"), [
+            ("reason", "QUOTA_EXCEEDED"),
+            ("domain", "example.com")
+        ]);
+        assert_eq!(event(&span, "status detail"), [(
+            "type_url",
+            "type.googleapis.com/example.Custom"
+        )]);
+    }
+
+    #[test]
+    fn invalid_base64_is_ignored() {
+        let span = record("not*base64");
+        assert!(span.events.is_empty());
+    }
+
+    #[test]
+    fn truncated_status_is_ignored() {
+        let status = status();
+        let span = record(&STANDARD_NO_PAD.encode(&status[..status.len() - 3]));
+        assert!(span.events.is_empty());
+    }
+}
@@ -0,0 +1,164 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::task::ready;
+
+use fastrace::prelude::*;
+use http::Request;
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// The future returned by the extractor of [`FastraceAsyncExtractLayer`].
+pub type BoxExtractFuture = Pin<Box<dyn Future<Output = Option<SpanContext>> + Send + 'static>>;
+
+/// Server layer deriving the parent span context of incoming requests asynchronously.
+///
+/// Some parents can only be derived with an async lookup, such as resolving an opaque
+/// correlation token from a local cache. This layer awaits the extractor before calling the
+/// inner service, and inserts the resulting [`SpanContext`] into the request extensions, where
+/// [`FastraceServerLayer`](crate::FastraceServerLayer) uses it as the parent. Add it outside
+/// of the server layer. When the extractor returns `None`, the server layer falls back to its
+/// own extraction.
+///
+/// ```
+/// use fastrace::prelude::*;
+/// use fastrace_tonic::FastraceAsyncExtractLayer;
+///
+/// async fn resolve(token: String) -> Option<SpanContext> {
+///     // Look the token up in a cache.
+///     None
+/// }
+///
+/// let layer = FastraceAsyncExtractLayer::new(|parts: &http::request::Parts| {
+///     let token = parts
+///         .headers
+///         .get("x-correlation-token")
+///         .and_then(|token| token.to_str().ok())
+///         .map(str::to_owned);
+///     Box::pin(async move { resolve(token?).await })
+/// });
+/// ```
+pub struct FastraceAsyncExtractLayer<F> {
+    extractor: Arc<F>,
+}
+
+impl<F> FastraceAsyncExtractLayer<F>
+where F: Fn(&http::request::Parts) -> BoxExtractFuture
+{
+    /// Creates a layer deriving parents with `extractor`.
+    pub fn new(extractor: F) -> Self {
+        Self {
+            extractor: Arc::new(extractor),
+        }
+    }
+}
+
+impl<F> Clone for FastraceAsyncExtractLayer<F> {
+    fn clone(&self) -> Self {
+        Self {
+            extractor: self.extractor.clone(),
+        }
+    }
+}
+
+impl<S, F> Layer<S> for FastraceAsyncExtractLayer<F> {
+    type Service = FastraceAsyncExtractService<S, F>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        FastraceAsyncExtractService {
+            service,
+            extractor: self.extractor.clone(),
+        }
+    }
+}
+
+/// Server-side service deriving the parent span context of incoming requests asynchronously.
+///
+/// See [`FastraceAsyncExtractLayer`] for details.
+pub struct FastraceAsyncExtractService<S, F> {
+    service: S,
+    extractor: Arc<F>,
+}
+
+impl<S: Clone, F> Clone for FastraceAsyncExtractService<S, F> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            extractor: self.extractor.clone(),
+        }
+    }
+}
+
+impl<S, F, ReqBody> Service<Request<ReqBody>> for FastraceAsyncExtractService<S, F>
+where
+    S: Service<Request<ReqBody>> + Clone,
+    F: Fn(&http::request::Parts) -> BoxExtractFuture,
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = FastraceAsyncExtractFuture<S, ReqBody>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let (parts, body) = req.into_parts();
+        let extraction = (self.extractor)(&parts);
+
+        // The service polled ready is the one to call once the extraction completes.
+        let clone = self.service.clone();
+        let service = std::mem::replace(&mut self.service, clone);
+
+        FastraceAsyncExtractFuture {
+            extraction: Some(extraction),
+            request: Some(Request::from_parts(parts, body)),
+            service,
+            inner: None,
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Response future of [`FastraceAsyncExtractService`].
+    ///
+    /// Awaits the extraction, then calls the inner service and awaits its response.
+    pub struct FastraceAsyncExtractFuture<S, ReqBody>
+    where
+        S: Service<Request<ReqBody>>,
+    {
+        extraction: Option<BoxExtractFuture>,
+        request: Option<Request<ReqBody>>,
+        service: S,
+        #[pin]
+        inner: Option<S::Future>,
+    }
+}
+
+impl<S, ReqBody> Future for FastraceAsyncExtractFuture<S, ReqBody>
+where S: Service<Request<ReqBody>>
+{
+    type Output = Result<S::Response, S::Error>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut this = self.project();
+
+        if let Some(extraction) = this.extraction {
+            let parent = ready!(extraction.as_mut().poll(cx));
+            *this.extraction = None;
+            let mut request = this.request.take().expect("extraction completes only once");
+            if let Some(parent) = parent {
+                request.extensions_mut().insert(parent);
+            }
+            this.inner.set(Some(this.service.call(request)));
+        }
+
+        this.inner
+            .as_mut()
+            .as_pin_mut()
+            .expect("the inner service is called once extraction completes")
+            .poll(cx)
+    }
+}
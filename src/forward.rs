@@ -0,0 +1,140 @@
+use std::borrow::Cow;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::task::ready;
+
+use fastrace::prelude::*;
+use http::HeaderName;
+use http::Request;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::carrier::Carrier;
+use crate::propagation;
+
+/// Server layer forwarding the trace context of incoming requests without creating spans.
+///
+/// This is a lightweight alternative to [`FastraceServerLayer`](crate::FastraceServerLayer) for
+/// services that only need to pass the trace context along to downstream calls. When an
+/// incoming request carries a valid context, the decoded
+/// [`SpanContext`](fastrace::prelude::SpanContext) is inserted into the request extensions, and the
+/// propagation headers are forwarded unchanged
+/// by [`FastraceClientLayer`](crate::FastraceClientLayer) on calls made while handling the
+/// request. Spans created by the handler itself take precedence over the forwarded headers.
+///
+/// This also makes L7 proxies built on tonic transparent: the downstream service continues the
+/// trace as a child of the upstream caller, with the same span id, rather than of a proxy hop.
+///
+/// ```
+/// let layer = fastrace_tonic::FastracePropagationLayer::new().with_proxy_span("proxy");
+/// ```
+#[derive(Clone, Default)]
+pub struct FastracePropagationLayer {
+    proxy_span_name: Option<Cow<'static, str>>,
+}
+
+impl FastracePropagationLayer {
+    /// Creates a layer that does not create any span.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a single span named `name` for each request carrying a valid context, as a child of
+    /// the upstream caller.
+    ///
+    /// The span is not made the local parent, so the propagation headers are still forwarded
+    /// unchanged and the span does not appear as a hop between the caller and the downstream
+    /// service.
+    pub fn with_proxy_span(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.proxy_span_name = Some(name.into());
+        self
+    }
+}
+
+impl<S> Layer<S> for FastracePropagationLayer {
+    type Service = FastracePropagationService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        FastracePropagationService {
+            service,
+            proxy_span_name: self.proxy_span_name.clone(),
+        }
+    }
+}
+
+/// Server-side service that forwards trace context without creating spans.
+///
+/// See [`FastracePropagationLayer`] for details.
+#[derive(Clone)]
+pub struct FastracePropagationService<S> {
+    service: S,
+    proxy_span_name: Option<Cow<'static, str>>,
+}
+
+impl<S, Body> Service<Request<Body>> for FastracePropagationService<S>
+where S: Service<Request<Body>>
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = FastracePropagationFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
+        let mut carrier = None;
+        let mut span = None;
+        if let Some(parent) = propagation::extract(req.headers()) {
+            let mut headers = Carrier::default();
+            for name in propagation::PROPAGATION_HEADERS {
+                if let Some(value) = req.headers().get(name) {
+                    headers.push(HeaderName::from_static(name), value.clone());
+                }
+            }
+            carrier = Some(Arc::new(headers));
+            span = self
+                .proxy_span_name
+                .clone()
+                .map(|name| Span::root(name, parent));
+            req.extensions_mut().insert(parent);
+        }
+
+        FastracePropagationFuture {
+            inner: self.service.call(req),
+            carrier,
+            span,
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Response future of [`FastracePropagationService`].
+    ///
+    /// The inner future is polled with the incoming propagation headers installed for
+    /// [`FastraceClientLayer`](crate::FastraceClientLayer) to forward. The proxy span, if any, is
+    /// submitted once the response is ready.
+    pub struct FastracePropagationFuture<F> {
+        #[pin]
+        inner: F,
+        carrier: Option<Arc<Carrier>>,
+        span: Option<Span>,
+    }
+}
+
+impl<F: Future> Future for FastracePropagationFuture<F> {
+    type Output = F::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        let _carrier_guard = this.carrier.as_ref().map(|carrier| carrier.set_current());
+        let result = ready!(this.inner.poll(cx));
+
+        this.span.take();
+        Poll::Ready(result)
+    }
+}
@@ -0,0 +1,157 @@
+use http::HeaderName;
+use http::HeaderValue;
+
+use crate::propagation::PROPAGATION_HEADERS;
+
+pub(crate) const BAGGAGE_HEADER: &str = "baggage";
+
+/// Which forwarded headers and baggage entries [`FastraceClientLayer`](crate::FastraceClientLayer)
+/// may send to a destination.
+///
+/// Rules apply to the headers the client layer forwards from the request being handled, such as
+/// the flavor header, and to the entries of a forwarded `baggage` header, matched by header name
+/// or baggage key. The propagation headers of the trace context itself are always sent. By
+/// default everything is allowed; once [`ForwardingRules::allow`] is used, only the allowed
+/// names are. Denied names are never sent.
+///
+/// ```
+/// use fastrace_tonic::ClientTracing;
+/// use fastrace_tonic::ForwardingRules;
+///
+/// let layer = ClientTracing::new()
+///     .with_forwarding_rules(
+///         "api.partner.example",
+///         ForwardingRules::new().deny("x-flavor").deny("user.id"),
+///     )
+///     .build();
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ForwardingRules {
+    allowed: Option<Vec<String>>,
+    denied: Vec<String>,
+}
+
+impl ForwardingRules {
+    /// Creates rules allowing everything.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allow a header name or baggage key, denying everything not allowed.
+    pub fn allow(mut self, name: impl Into<String>) -> Self {
+        self.allowed
+            .get_or_insert_with(Vec::new)
+            .push(name.into().to_ascii_lowercase());
+        self
+    }
+
+    /// Deny a header name or baggage key.
+    pub fn deny(mut self, name: impl Into<String>) -> Self {
+        self.denied.push(name.into().to_ascii_lowercase());
+        self
+    }
+
+    fn is_allowed(&self, name: &str) -> bool {
+        let name = name.trim().to_ascii_lowercase();
+        if self.denied.contains(&name) {
+            return false;
+        }
+        match &self.allowed {
+            Some(allowed) => allowed.contains(&name),
+            None => true,
+        }
+    }
+
+    /// Returns the value to forward for a header, or `None` to drop it.
+    ///
+    /// The entries of a `baggage` header are filtered by key rather than by header name, and the
+    /// propagation headers of the trace context are always forwarded.
+    pub(crate) fn filter(&self, name: &HeaderName, value: &HeaderValue) -> Option<HeaderValue> {
+        if PROPAGATION_HEADERS.contains(&name.as_str()) {
+            return Some(value.clone());
+        }
+        if name != BAGGAGE_HEADER {
+            return self.is_allowed(name.as_str()).then(|| value.clone());
+        }
+
+        let baggage = value.to_str().ok()?;
+        let entries: Vec<&str> = baggage
+            .split(',')
+            .filter(|entry| {
+                let key = entry.split(['=', ';']).next().unwrap_or_default();
+                self.is_allowed(key)
+            })
+            .collect();
+        if entries.is_empty() {
+            return None;
+        }
+        HeaderValue::from_str(&entries.join(",")).ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter(rules: &ForwardingRules, name: &'static str, value: &'static str) -> Option<String> {
+        rules
+            .filter(
+                &HeaderName::from_static(name),
+                &HeaderValue::from_static(value),
+            )
+            .map(|value| value.to_str().unwrap().to_owned())
+    }
+
+    #[test]
+    fn allow_list_keeps_trace_context() {
+        let rules = ForwardingRules::new().allow("x-flavor");
+
+        let traceparent = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01";
+        assert_eq!(
+            filter(&rules, "traceparent", traceparent).as_deref(),
+            Some(traceparent)
+        );
+        assert_eq!(
+            filter(&rules, "tracestate", "congo=t61rcWkgMzE").as_deref(),
+            Some("congo=t61rcWkgMzE")
+        );
+        assert_eq!(
+            filter(&rules, "x-flavor", "canary").as_deref(),
+            Some("canary")
+        );
+        assert_eq!(filter(&rules, "x-request-id", "42"), None);
+    }
+
+    #[test]
+    fn deny_does_not_apply_to_trace_context() {
+        let rules = ForwardingRules::new().deny("traceparent").deny("X-Flavor");
+
+        assert!(
+            filter(
+                &rules,
+                "traceparent",
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01"
+            )
+            .is_some()
+        );
+        assert_eq!(filter(&rules, "x-flavor", "canary"), None);
+        assert_eq!(filter(&rules, "x-request-id", "42").as_deref(), Some("42"));
+    }
+
+    #[test]
+    fn baggage_is_filtered_by_key() {
+        let rules = ForwardingRules::new().deny("user.id");
+
+        assert_eq!(
+            filter(&rules, "baggage", "user.id=42,region=eu;ttl=60").as_deref(),
+            Some("region=eu;ttl=60")
+        );
+        assert_eq!(filter(&rules, "baggage", "user.id=42"), None);
+
+        let rules = ForwardingRules::new().allow("region");
+        assert_eq!(
+            filter(&rules, "baggage", "user.id=42,region=eu").as_deref(),
+            Some("region=eu")
+        );
+    }
+}
@@ -0,0 +1,163 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::task::ready;
+
+use fastrace::prelude::*;
+use http::Uri;
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Client connector layer creating spans for TLS handshakes.
+///
+/// Wrap a connector establishing TLS connections, such as one built with `tokio-rustls` and
+/// passed to tonic's `Endpoint::connect_with_connector`, to create a `tls handshake` span for
+/// every new connection, as a child of the current local parent or as a new trace. The span
+/// covers the whole connector call, and the connector is polled with the span as the local
+/// parent, so wrapping its TCP connector with
+/// [`FastraceConnectLayer`](crate::FastraceConnectLayer) separates the TCP connection from the
+/// handshake. The requested host is recorded as the `server.address` property and, once
+/// connected, the protocol negotiated through ALPN returned by `negotiated_protocol` as
+/// `tls.next_protocol`. A failed handshake adds a `tls handshake failed` event.
+///
+/// ```
+/// use fastrace_tonic::FastraceTlsConnectLayer;
+///
+/// struct TlsConnection {
+///     alpn: Option<Vec<u8>>,
+/// }
+///
+/// let layer = FastraceTlsConnectLayer::new(|connection: &TlsConnection| connection.alpn.clone());
+/// ```
+pub struct FastraceTlsConnectLayer<F> {
+    negotiated_protocol: Arc<F>,
+}
+
+impl<F> FastraceTlsConnectLayer<F> {
+    /// Creates a layer reading the ALPN protocol of a connection with `negotiated_protocol`.
+    pub fn new(negotiated_protocol: F) -> Self {
+        Self {
+            negotiated_protocol: Arc::new(negotiated_protocol),
+        }
+    }
+}
+
+impl<F> Clone for FastraceTlsConnectLayer<F> {
+    fn clone(&self) -> Self {
+        Self {
+            negotiated_protocol: self.negotiated_protocol.clone(),
+        }
+    }
+}
+
+impl<C, F> Layer<C> for FastraceTlsConnectLayer<F> {
+    type Service = FastraceTlsConnectService<C, F>;
+
+    fn layer(&self, connector: C) -> Self::Service {
+        FastraceTlsConnectService {
+            connector,
+            negotiated_protocol: self.negotiated_protocol.clone(),
+        }
+    }
+}
+
+/// Client connector creating spans for TLS handshakes.
+///
+/// See [`FastraceTlsConnectLayer`] for details.
+pub struct FastraceTlsConnectService<C, F> {
+    connector: C,
+    negotiated_protocol: Arc<F>,
+}
+
+impl<C: Clone, F> Clone for FastraceTlsConnectService<C, F> {
+    fn clone(&self) -> Self {
+        Self {
+            connector: self.connector.clone(),
+            negotiated_protocol: self.negotiated_protocol.clone(),
+        }
+    }
+}
+
+impl<C, F> Service<Uri> for FastraceTlsConnectService<C, F>
+where
+    C: Service<Uri>,
+    F: Fn(&C::Response) -> Option<Vec<u8>>,
+{
+    type Response = C::Response;
+    type Error = C::Error;
+    type Future = FastraceTlsConnectFuture<C::Future, F>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.connector.poll_ready(cx)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let span = match SpanContext::current_local_parent() {
+            Some(_) => Span::enter_with_local_parent("tls handshake"),
+            None => Span::root("tls handshake", SpanContext::random()),
+        };
+        if let Some(host) = uri.host() {
+            span.add_property(|| ("server.address", host.to_owned()));
+        }
+
+        let inner = {
+            let _guard = span.set_local_parent();
+            self.connector.call(uri)
+        };
+        FastraceTlsConnectFuture {
+            inner,
+            span: Some(span),
+            negotiated_protocol: self.negotiated_protocol.clone(),
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Response future of [`FastraceTlsConnectService`].
+    pub struct FastraceTlsConnectFuture<Fut, F> {
+        #[pin]
+        inner: Fut,
+        span: Option<Span>,
+        negotiated_protocol: Arc<F>,
+    }
+}
+
+impl<Fut, F, T, E> Future for FastraceTlsConnectFuture<Fut, F>
+where
+    Fut: Future<Output = Result<T, E>>,
+    F: Fn(&T) -> Option<Vec<u8>>,
+{
+    type Output = Fut::Output;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        let result = {
+            let _guard = this.span.as_ref().map(|span| span.set_local_parent());
+            ready!(this.inner.poll(cx))
+        };
+
+        if let Some(span) = this.span.take() {
+            match &result {
+                Ok(connection) => {
+                    if let Some(protocol) = (this.negotiated_protocol)(connection) {
+                        span.add_property(|| {
+                            (
+                                "tls.next_protocol",
+                                String::from_utf8_lossy(&protocol).into_owned(),
+                            )
+                        });
+                    }
+                }
+                Err(_) => {
+                    span.add_property(|| ("error", "true"));
+                    span.add_event(Event::new("tls handshake failed"));
+                }
+            }
+        }
+
+        Poll::Ready(result)
+    }
+}
@@ -0,0 +1,71 @@
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
+
+/// The number of request spans created by the server layer and not yet submitted.
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+static LOCK: Mutex<()> = Mutex::new(());
+static QUIESCENT: Condvar = Condvar::new();
+
+/// Registers a request span as in flight until dropped.
+pub(crate) struct InFlightGuard(());
+
+impl InFlightGuard {
+    pub(crate) fn new() -> Self {
+        IN_FLIGHT.fetch_add(1, Ordering::AcqRel);
+        Self(())
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if IN_FLIGHT.fetch_sub(1, Ordering::AcqRel) == 1 {
+            // Taking the lock orders the notification after a waiter's check of the count.
+            let _lock = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+            QUIESCENT.notify_all();
+        }
+    }
+}
+
+/// Blocks until every request span created by
+/// [`FastraceServerLayer`](crate::FastraceServerLayer) has been submitted, or `timeout` elapses.
+///
+/// Returns whether all request spans were submitted. Spans are submitted once their request has
+/// completed and every [`RequestSpan`](crate::RequestSpan) handle has been dropped. Call this in a
+/// custom shutdown sequence after the server stopped accepting requests and before
+/// [`fastrace::flush`], from a blocking context such as `tokio::task::spawn_blocking`.
+pub fn await_quiescence(timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    let mut lock = LOCK.lock().unwrap_or_else(PoisonError::into_inner);
+    while IN_FLIGHT.load(Ordering::Acquire) > 0 {
+        let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+            return false;
+        };
+        lock = QUIESCENT
+            .wait_timeout(lock, remaining)
+            .unwrap_or_else(PoisonError::into_inner)
+            .0;
+    }
+    true
+}
+
+/// Waits for in-flight request spans with [`await_quiescence`], then flushes the reporter.
+///
+/// Returns whether all request spans were submitted before the flush. Like
+/// [`await_quiescence`], this blocks the current thread.
+///
+/// ```no_run
+/// use std::time::Duration;
+///
+/// // After the server has shut down:
+/// fastrace_tonic::flush_on_shutdown(Duration::from_secs(5));
+/// ```
+pub fn flush_on_shutdown(timeout: Duration) -> bool {
+    let quiescent = await_quiescence(timeout);
+    fastrace::flush();
+    quiescent
+}
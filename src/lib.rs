@@ -1,14 +1,110 @@
 #![doc = include_str!("../README.md")]
 
-use std::sync::Arc;
-use std::task::Context;
-use std::task::Poll;
-
-use fastrace::prelude::*;
-use http::HeaderValue;
-use http::Request;
-use tower_layer::Layer;
-use tower_service::Service;
+mod body;
+mod cache;
+mod capture;
+mod carrier;
+mod client;
+#[cfg(feature = "tonic")]
+mod codec;
+#[cfg(feature = "compat-http02")]
+pub mod compat;
+mod connect;
+mod connection;
+#[cfg(feature = "prost-types")]
+mod details;
+mod extract;
+mod forward;
+mod forwarding;
+mod handshake;
+mod inflight;
+mod name;
+mod peer;
+pub mod propagation;
+mod reporter;
+mod route;
+mod sampling;
+mod server;
+mod socket;
+mod span;
+mod status;
+mod summary;
+#[cfg(test)]
+mod testing;
+mod timeout;
+mod toggle;
+#[cfg(feature = "tonic")]
+mod upload;
+mod watchdog;
+pub mod wire_compat;
+pub use body::FastraceClientBody;
+pub use body::FastraceRequestBody;
+pub use body::FastraceServerBody;
+pub use cache::CacheStatus;
+pub use client::ClientTracing;
+pub use client::ExistingContextPolicy;
+pub use client::FastraceClientFuture;
+pub use client::FastraceClientLayer;
+pub use client::FastraceClientService;
+pub use client::FastraceClientSpanLayer;
+pub use client::FastraceClientSpanService;
+pub use client::MissingParentPolicy;
+#[cfg(feature = "tonic")]
+pub use codec::FastraceCodec;
+#[cfg(feature = "tonic")]
+pub use codec::FastraceDecoder;
+#[cfg(feature = "tonic")]
+pub use codec::FastraceEncoder;
+pub use connect::FastraceConnectFuture;
+pub use connect::FastraceConnectLayer;
+pub use connect::FastraceConnectService;
+pub use connect::FastraceResolveFuture;
+pub use connect::FastraceResolver;
+pub use connection::FastraceConnectionService;
+pub use connection::FastraceMakeFuture;
+pub use connection::FastraceMakeService;
+#[cfg(feature = "transport")]
+pub use connection::record_connect_info;
+pub use extract::BoxExtractFuture;
+pub use extract::FastraceAsyncExtractFuture;
+pub use extract::FastraceAsyncExtractLayer;
+pub use extract::FastraceAsyncExtractService;
+pub use forward::FastracePropagationFuture;
+pub use forward::FastracePropagationLayer;
+pub use forward::FastracePropagationService;
+pub use forwarding::ForwardingRules;
+pub use handshake::FastraceTlsConnectFuture;
+pub use handshake::FastraceTlsConnectLayer;
+pub use handshake::FastraceTlsConnectService;
+pub use inflight::await_quiescence;
+pub use inflight::flush_on_shutdown;
+pub use peer::ForwardedFor;
+pub use peer::InProcessPeer;
+pub use peer::socket_addr_from_extensions;
+#[cfg(feature = "tonic")]
+pub use propagation::extract_from_metadata;
+#[cfg(feature = "tonic")]
+pub use propagation::inject_into_metadata;
+pub use reporter::ChildSpanCountReporter;
+pub use reporter::PriorityRetentionReporter;
+pub use route::RouteConfig;
+pub use route::RoutePattern;
+pub use server::ConfigError;
+pub use server::ExtractionDecision;
+pub use server::FastraceServerFuture;
+pub use server::FastraceServerLayer;
+pub use server::FastraceServerService;
+pub use server::MissingContextPolicy;
+pub use server::ServerTracing;
+pub use span::RequestSpan;
+pub use span::sync_scope;
+pub use status::StatusInfo;
+pub use status::TraceIdInErrors;
+pub use toggle::FastraceToggle;
+#[cfg(feature = "tonic")]
+pub use upload::FastraceUploadProgressLayer;
+#[cfg(feature = "tonic")]
+pub use upload::FastraceUploadProgressService;
 
 /// The standard [W3C Trace Context](https://www.w3.org/TR/trace-context/) header name for passing trace information.
 ///
@@ -16,137 +112,31 @@ use tower_service::Service;
 /// the W3C Trace Context specification.
 pub const TRACEPARENT_HEADER: &str = "traceparent";
 
-type SpanContextExtractor =
-    Arc<dyn Fn(&http::HeaderMap) -> Option<SpanContext> + Send + Sync + 'static>;
+/// The [W3C Trace Context](https://www.w3.org/TR/trace-context/) header name for vendor-specific
+/// trace information.
+pub const TRACESTATE_HEADER: &str = "tracestate";
 
-/// Server layer for intercepting and processing trace context in incoming requests.
+/// The conventional header name for forcing a request to be traced.
 ///
-/// This layer extracts tracing context from incoming requests and creates a new span
-/// for each request. Add this to your tonic server to automatically handle trace context
-/// propagation. By default, the layer uses the `traceparent` header to extract a span
-/// context and falls back to a random context when the header is missing or invalid.
-/// If the configured extractor returns `None`, a noop span is used.
-#[derive(Clone)]
-pub struct FastraceServerLayer {
-    span_context_extractor: SpanContextExtractor,
-}
-
-impl Default for FastraceServerLayer {
-    fn default() -> Self {
-        Self {
-            span_context_extractor: Arc::new(|headers| {
-                headers
-                    .get(TRACEPARENT_HEADER)
-                    .and_then(|traceparent| {
-                        SpanContext::decode_w3c_traceparent(traceparent.to_str().ok()?)
-                    })
-                    .or_else(|| Some(SpanContext::random()))
-            }),
-        }
-    }
-}
-
-impl FastraceServerLayer {
-    /// Configure a custom span context extractor.
-    ///
-    /// Return `None` to keep the span as noop.
-    pub fn with_span_context_extractor<F>(mut self, f: F) -> Self
-    where F: Fn(&http::HeaderMap) -> Option<SpanContext> + Send + Sync + 'static {
-        self.span_context_extractor = Arc::new(f);
-        self
-    }
-}
-
-impl<S> Layer<S> for FastraceServerLayer {
-    type Service = FastraceServerService<S>;
+/// See [`ServerTracing::with_force_trace`].
+pub const FORCE_TRACE_HEADER: &str = "x-fastrace-force-trace";
 
-    fn layer(&self, service: S) -> Self::Service {
-        FastraceServerService {
-            service,
-            span_context_extractor: self.span_context_extractor.clone(),
-        }
-    }
-}
-
-/// Server-side service that handles trace context propagation.
+/// The conventional header name for opting a request out of tracing.
 ///
-/// This service extracts trace context from incoming requests and creates
-/// spans to track the request processing. It wraps the inner service and augments
-/// it with tracing capabilities.
-#[derive(Clone)]
-pub struct FastraceServerService<S> {
-    service: S,
-    span_context_extractor: SpanContextExtractor,
-}
-
-impl<S, Body> Service<Request<Body>> for FastraceServerService<S>
-where S: Service<Request<Body>>
-{
-    type Response = S::Response;
-    type Error = S::Error;
-    type Future = fastrace::future::InSpan<S::Future>;
-
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(cx)
-    }
-
-    fn call(&mut self, req: Request<Body>) -> Self::Future {
-        let parent = (self.span_context_extractor)(req.headers());
+/// See [`ServerTracing::with_disable_header`].
+pub const DISABLE_TRACE_HEADER: &str = "x-fastrace-disable";
 
-        let span = if let Some(parent) = parent {
-            Span::root(req.uri().to_string(), parent)
-        } else {
-            Span::noop()
-        };
-
-        self.service.call(req).in_span(span)
-    }
-}
-
-/// Client layer for injecting trace context into outgoing requests.
+/// A ready-made request filter that excludes the gRPC health checking and reflection services.
 ///
-/// This layer adds the current trace context to outgoing requests,
-/// allowing the receiving service to continue the same trace. Add this
-/// to your tonic client to automatically propagate trace context.
-#[derive(Clone)]
-pub struct FastraceClientLayer;
-
-impl<S> Layer<S> for FastraceClientLayer {
-    type Service = FastraceClientService<S>;
-
-    fn layer(&self, service: S) -> Self::Service {
-        FastraceClientService { service }
-    }
-}
-
-/// Client-side service that handles trace context propagation.
+/// Requests to `grpc.health.v1.Health/*` and `grpc.reflection.*` are typically issued by
+/// orchestrators and tooling at a high rate and rarely carry useful traces.
 ///
-/// This service injects the current trace context into outgoing requests,
-/// allowing distributed tracing across service boundaries.
-#[derive(Clone)]
-pub struct FastraceClientService<S> {
-    service: S,
-}
-
-impl<S, Body> Service<Request<Body>> for FastraceClientService<S>
-where S: Service<Request<Body>>
-{
-    type Response = S::Response;
-    type Error = S::Error;
-    type Future = S::Future;
-
-    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.service.poll_ready(cx)
-    }
-
-    fn call(&mut self, mut req: Request<Body>) -> Self::Future {
-        if let Some(current) = SpanContext::current_local_parent() {
-            req.headers_mut().insert(
-                TRACEPARENT_HEADER,
-                HeaderValue::from_str(&current.encode_w3c_traceparent()).unwrap(),
-            );
-        }
-
-        self.service.call(req)
-    }
+/// ```
+/// let layer = fastrace_tonic::ServerTracing::new()
+///     .with_filter(fastrace_tonic::exclude_health_and_reflection)
+///     .build();
+/// ```
+pub fn exclude_health_and_reflection(parts: &http::request::Parts) -> bool {
+    let path = parts.uri.path();
+    !(path.starts_with("/grpc.health.v1.Health/") || path.starts_with("/grpc.reflection."))
 }
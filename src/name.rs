@@ -0,0 +1,240 @@
+use std::borrow::Cow;
+
+/// A span name template such as `{http.method} {rpc.service}/{rpc.method}`, parsed once.
+#[derive(Clone, Debug)]
+pub(crate) struct SpanNameTemplate {
+    segments: Vec<Segment>,
+}
+
+#[derive(Clone, Debug)]
+enum Segment {
+    Literal(String),
+    HttpMethod,
+    UrlPath,
+    ServerAddress,
+    RpcService,
+    RpcMethod,
+}
+
+impl SpanNameTemplate {
+    /// Parses `template`. Unknown placeholders and unbalanced braces are kept verbatim.
+    pub(crate) fn parse(template: &str) -> Self {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut rest = template;
+
+        while let Some(start) = rest.find('{') {
+            literal.push_str(&rest[..start]);
+            let Some(len) = rest[start..].find('}') else {
+                break;
+            };
+            let placeholder = &rest[start + 1..start + len];
+            let field = match placeholder {
+                "http.method" => Some(Segment::HttpMethod),
+                "url.path" => Some(Segment::UrlPath),
+                "server.address" => Some(Segment::ServerAddress),
+                "rpc.service" => Some(Segment::RpcService),
+                "rpc.method" => Some(Segment::RpcMethod),
+                _ => None,
+            };
+            match field {
+                Some(field) => {
+                    if !literal.is_empty() {
+                        segments.push(Segment::Literal(std::mem::take(&mut literal)));
+                    }
+                    segments.push(field);
+                }
+                None => literal.push_str(&rest[start..=start + len]),
+            }
+            rest = &rest[start + len + 1..];
+        }
+        literal.push_str(rest);
+        if !literal.is_empty() {
+            segments.push(Segment::Literal(literal));
+        }
+
+        Self { segments }
+    }
+
+    /// Renders the span name of a request. Placeholders without a value render as empty.
+    pub(crate) fn render(&self, parts: &http::request::Parts) -> Cow<'static, str> {
+        let path = parts.uri.path();
+        let (service, method) = grpc_method(path).unwrap_or_default();
+
+        let mut name = String::new();
+        for segment in &self.segments {
+            name.push_str(match segment {
+                Segment::Literal(literal) => literal.as_str(),
+                Segment::HttpMethod => parts.method.as_str(),
+                Segment::UrlPath => path,
+                Segment::ServerAddress => server_address(parts).unwrap_or_default(),
+                Segment::RpcService => service,
+                Segment::RpcMethod => method,
+            });
+        }
+        name.into()
+    }
+}
+
+/// Returns `{method} {route}` for requests routed by axum, such as `GET /users/{id}`, from the
+/// `MatchedPath` axum inserts into the request extensions.
+#[cfg(feature = "axum")]
+pub(crate) fn matched_path(parts: &http::request::Parts) -> Option<Cow<'static, str>> {
+    let path = parts.extensions.get::<axum::extract::MatchedPath>()?;
+    Some(format!("{} {}", parts.method, path.as_str()).into())
+}
+
+#[cfg(not(feature = "axum"))]
+pub(crate) fn matched_path(_parts: &http::request::Parts) -> Option<Cow<'static, str>> {
+    None
+}
+
+/// Replaces numeric and UUID path segments with `{id}` and `{uuid}` placeholders.
+pub(crate) fn normalize_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| {
+            if !segment.is_empty() && segment.bytes().all(|b| b.is_ascii_digit()) {
+                "{id}"
+            } else if is_uuid(segment) {
+                "{uuid}"
+            } else {
+                segment
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn is_uuid(segment: &str) -> bool {
+    let bytes = segment.as_bytes();
+    bytes.len() == 36
+        && bytes.iter().enumerate().all(|(i, b)| match i {
+            8 | 13 | 18 | 23 => *b == b'-',
+            _ => b.is_ascii_hexdigit(),
+        })
+}
+
+/// Splits a gRPC path such as `/pkg.Service/Method` into the service and method names.
+pub(crate) fn grpc_method(path: &str) -> Option<(&str, &str)> {
+    let (service, method) = path.strip_prefix('/')?.split_once('/')?;
+    (!service.is_empty() && !method.is_empty() && !method.contains('/'))
+        .then_some((service, method))
+}
+
+/// Returns the host the request was sent to, from the request URI or else the `Host` header,
+/// without the port.
+pub(crate) fn server_address(parts: &http::request::Parts) -> Option<&str> {
+    if let Some(host) = parts.uri.host() {
+        return Some(host);
+    }
+    let host = parts.headers.get(http::header::HOST)?.to_str().ok()?;
+    // Bracketed IPv6 addresses contain colons of their own.
+    Some(match host.rfind(':') {
+        Some(port) if !host[port..].contains(']') => &host[..port],
+        _ => host,
+    })
+}
+
+/// Truncates `name` to at most `max_len` bytes, on a character boundary.
+pub(crate) fn truncate(name: Cow<'static, str>, max_len: usize) -> Cow<'static, str> {
+    if name.len() <= max_len {
+        return name;
+    }
+    let mut end = max_len;
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    match name {
+        Cow::Borrowed(name) => Cow::Borrowed(&name[..end]),
+        Cow::Owned(mut name) => {
+            name.truncate(end);
+            Cow::Owned(name)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_ids() {
+        assert_eq!(
+            normalize_path("/users/42/posts/7"),
+            "/users/{id}/posts/{id}"
+        );
+        assert_eq!(
+            normalize_path("/orders/0f8fad5b-d9cb-469f-a165-70867728950e"),
+            "/orders/{uuid}"
+        );
+        assert_eq!(normalize_path("/v2/users/"), "/v2/users/");
+        assert_eq!(normalize_path("/pkg.Service/Method"), "/pkg.Service/Method");
+        assert_eq!(
+            normalize_path("/orders/0f8fad5b-d9cb-469f-a165-70867728950"),
+            "/orders/0f8fad5b-d9cb-469f-a165-70867728950"
+        );
+    }
+
+    #[test]
+    fn splits_grpc_methods() {
+        assert_eq!(
+            grpc_method("/pkg.Service/Method"),
+            Some(("pkg.Service", "Method"))
+        );
+        assert_eq!(grpc_method("pkg.Service/Method"), None);
+        assert_eq!(grpc_method("/pkg.Service/"), None);
+        assert_eq!(grpc_method("/a/b/c"), None);
+    }
+
+    fn parts(uri: &str, host: Option<&'static str>) -> http::request::Parts {
+        let mut req = http::Request::builder().uri(uri);
+        if let Some(host) = host {
+            req = req.header(http::header::HOST, host);
+        }
+        req.body(()).unwrap().into_parts().0
+    }
+
+    #[test]
+    fn server_address_falls_back_to_host_header() {
+        let absolute = parts(
+            "http://api.example.com:8080/pkg.Service/Method",
+            Some("other"),
+        );
+        assert_eq!(server_address(&absolute), Some("api.example.com"));
+        assert_eq!(
+            server_address(&parts("/x", Some("example.com:443"))),
+            Some("example.com")
+        );
+        assert_eq!(
+            server_address(&parts("/x", Some("example.com"))),
+            Some("example.com")
+        );
+        assert_eq!(
+            server_address(&parts("/x", Some("[::1]:50051"))),
+            Some("[::1]")
+        );
+        assert_eq!(server_address(&parts("/x", Some("[::1]"))), Some("[::1]"));
+        assert_eq!(server_address(&parts("/x", None)), None);
+    }
+
+    #[test]
+    fn renders_server_address_placeholder() {
+        let template = SpanNameTemplate::parse("{http.method} {server.address}{url.path}");
+        let parts = parts("/pkg.Service/Method", Some("grpc.internal:50051"));
+        assert_eq!(
+            template.render(&parts),
+            "GET grpc.internal/pkg.Service/Method"
+        );
+    }
+
+    #[test]
+    fn truncates_on_char_boundary() {
+        assert_eq!(truncate(Cow::Borrowed("short"), 10), "short");
+        assert_eq!(truncate(Cow::Borrowed("exactly"), 7), "exactly");
+        assert_eq!(truncate(Cow::Borrowed("abcdef"), 3), "abc");
+        // `é` is two bytes long.
+        assert_eq!(truncate(Cow::Borrowed("café"), 4), "caf");
+        assert_eq!(truncate(Cow::Owned("日本語".to_owned()), 7), "日本");
+        assert_eq!(truncate(Cow::Owned("日本語".to_owned()), 2), "");
+    }
+}
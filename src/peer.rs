@@ -0,0 +1,260 @@
+use std::borrow::Cow;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use fastrace::prelude::*;
+
+type PeerAddressExtractor =
+    Arc<dyn Fn(&http::Extensions) -> Option<SocketAddr> + Send + Sync + 'static>;
+
+/// A ready-made peer address extractor reading a [`SocketAddr`] from the request extensions.
+///
+/// Use it with [`ServerTracing::with_peer_address`](crate::ServerTracing::with_peer_address)
+/// when an outer layer or a custom accept loop inserts the remote address as a plain
+/// [`SocketAddr`].
+///
+/// ```
+/// let layer = fastrace_tonic::ServerTracing::new()
+///     .with_peer_address(fastrace_tonic::socket_addr_from_extensions)
+///     .build();
+/// ```
+pub fn socket_addr_from_extensions(extensions: &http::Extensions) -> Option<SocketAddr> {
+    extensions.get::<SocketAddr>().copied()
+}
+
+/// Reads the remote address from the connect info tonic inserts into the request extensions,
+/// either [`TcpConnectInfo`](tonic::transport::server::TcpConnectInfo) or, with the `tls`
+/// feature, `TlsConnectInfo<TcpConnectInfo>`.
+#[cfg(feature = "transport")]
+pub(crate) fn connect_info_remote_addr(extensions: &http::Extensions) -> Option<SocketAddr> {
+    use tonic::transport::server::TcpConnectInfo;
+
+    if let Some(info) = extensions.get::<TcpConnectInfo>() {
+        return info.remote_addr();
+    }
+    #[cfg(feature = "tls")]
+    if let Some(info) = extensions.get::<tonic::transport::server::TlsConnectInfo<TcpConnectInfo>>()
+    {
+        return info.get_ref().remote_addr();
+    }
+    None
+}
+
+/// Records a TCP connection, found from the connect info tonic inserts into the request
+/// extensions, as the `network.transport` property set to `tcp`, with the `tls.established`
+/// property set to `true` for TLS connections.
+#[cfg(feature = "transport")]
+pub(crate) fn record_tcp_transport(span: &Span, extensions: &http::Extensions) {
+    use tonic::transport::server::TcpConnectInfo;
+
+    #[cfg(feature = "tls")]
+    if extensions
+        .get::<tonic::transport::server::TlsConnectInfo<TcpConnectInfo>>()
+        .is_some()
+    {
+        span.add_properties(|| [("network.transport", "tcp"), ("tls.established", "true")]);
+    }
+    if extensions.get::<TcpConnectInfo>().is_some() {
+        span.add_property(|| ("network.transport", "tcp"));
+    }
+}
+
+pub(crate) fn record_socket_addr(span: &Span, addr: SocketAddr) {
+    span.add_properties(|| {
+        [
+            ("network.peer.address", addr.ip().to_string()),
+            ("network.peer.port", addr.port().to_string()),
+        ]
+    });
+}
+
+/// Records a Unix domain socket connection, found from tonic's `UdsConnectInfo`, as the
+/// `network.transport` property set to `unix`, and the credentials of the peer process as the
+/// `unix.peer.uid`, `unix.peer.gid` and `unix.peer.pid` properties.
+#[cfg(all(feature = "transport", unix))]
+pub(crate) fn record_uds_peer(span: &Span, extensions: &http::Extensions) {
+    let Some(info) = extensions.get::<tonic::transport::server::UdsConnectInfo>() else {
+        return;
+    };
+    span.add_property(|| ("network.transport", "unix"));
+    let Some(cred) = info.peer_cred else {
+        return;
+    };
+    span.add_properties(|| {
+        [
+            ("unix.peer.uid", cred.uid().to_string()),
+            ("unix.peer.gid", cred.gid().to_string()),
+        ]
+    });
+    if let Some(pid) = cred.pid() {
+        span.add_property(|| ("unix.peer.pid", pid.to_string()));
+    }
+}
+
+/// Records the subject common name and the DNS and URI subject alternative names of the
+/// client certificate found in tonic's `TlsConnectInfo` as the `tls.client.subject_cn` and
+/// `tls.client.san` properties. A `spiffe://` URI name is also recorded as the `spiffe.id`
+/// property, identifying the workload in SPIFFE-based meshes.
+#[cfg(feature = "mtls")]
+pub(crate) fn record_peer_identity(span: &Span, extensions: &http::Extensions) {
+    use tonic::transport::server::TcpConnectInfo;
+    use tonic::transport::server::TlsConnectInfo;
+    use x509_parser::extensions::GeneralName;
+
+    let Some(certs) = extensions
+        .get::<TlsConnectInfo<TcpConnectInfo>>()
+        .and_then(TlsConnectInfo::peer_certs)
+    else {
+        return;
+    };
+    let Some(Ok((_, cert))) = certs
+        .first()
+        .map(|leaf| x509_parser::parse_x509_certificate(leaf.as_ref()))
+    else {
+        return;
+    };
+
+    if let Some(cn) = cert
+        .subject()
+        .iter_common_name()
+        .next()
+        .and_then(|cn| cn.as_str().ok())
+    {
+        span.add_property(|| ("tls.client.subject_cn", cn.to_owned()));
+    }
+    if let Ok(Some(san)) = cert.subject_alternative_name() {
+        let names = san
+            .value
+            .general_names
+            .iter()
+            .filter_map(|name| match name {
+                GeneralName::DNSName(name) | GeneralName::URI(name) => Some(*name),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        if !names.is_empty() {
+            span.add_property(|| ("tls.client.san", names.join(",")));
+        }
+        if let Some(spiffe_id) = san.value.general_names.iter().find_map(|name| match name {
+            GeneralName::URI(uri) if uri.starts_with("spiffe://") => Some(*uri),
+            _ => None,
+        }) {
+            span.add_property(|| ("spiffe.id", spiffe_id.to_owned()));
+        }
+    }
+}
+
+/// The hops of the forwarded-for chain recorded by
+/// [`ServerTracing::with_forwarded_for`](crate::ServerTracing::with_forwarded_for).
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ForwardedFor {
+    /// Every hop, comma-separated, starting from the original client.
+    Chain,
+    /// The first hop, which is the original client as reported by the first proxy.
+    First,
+    /// The last hop, which was added by the proxy closest to the server.
+    Last,
+}
+
+impl ForwardedFor {
+    /// Returns the selected hops of the `forwarded` header, or of the `x-forwarded-for` header
+    /// when the former is absent.
+    pub(crate) fn select(self, headers: &http::HeaderMap) -> Option<String> {
+        let mut hops = forwarded_hops(headers);
+        if hops.is_empty() {
+            hops = headers
+                .get_all("x-forwarded-for")
+                .iter()
+                .filter_map(|value| value.to_str().ok())
+                .flat_map(|value| value.split(','))
+                .map(str::trim)
+                .filter(|hop| !hop.is_empty())
+                .collect();
+        }
+        match self {
+            ForwardedFor::Chain => (!hops.is_empty()).then(|| hops.join(",")),
+            ForwardedFor::First => hops.first().map(|hop| (*hop).to_owned()),
+            ForwardedFor::Last => hops.last().map(|hop| (*hop).to_owned()),
+        }
+    }
+}
+
+/// Returns the `for` parameters of the RFC 7239 `forwarded` header.
+fn forwarded_hops(headers: &http::HeaderMap) -> Vec<&str> {
+    headers
+        .get_all(http::header::FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for")
+                    .then(|| value.trim_matches('"'))
+            })
+        })
+        .collect()
+}
+
+/// The identity of the peer of an in-process connection, such as a `tokio::io::duplex` stream
+/// used to wire services of a modular monolith.
+///
+/// In-process connections have no socket address, so when this is found in the request
+/// extensions, [`FastraceServerLayer`](crate::FastraceServerLayer) records its name as the
+/// `network.peer.address` property and tags the span with `network.transport` set to `inproc`,
+/// keeping in-process traces consistent with networked ones. The simplest way to insert it is to
+/// return it as the connect info of the in-process stream, which tonic inserts into the request
+/// extensions:
+///
+/// ```ignore
+/// impl tonic::transport::server::Connected for InProcessStream {
+///     type ConnectInfo = fastrace_tonic::InProcessPeer;
+///
+///     fn connect_info(&self) -> Self::ConnectInfo {
+///         fastrace_tonic::InProcessPeer::new("billing")
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug)]
+pub struct InProcessPeer {
+    name: Cow<'static, str>,
+}
+
+impl InProcessPeer {
+    /// Creates a peer identity, typically the name of the calling component.
+    pub fn new(name: impl Into<Cow<'static, str>>) -> Self {
+        Self { name: name.into() }
+    }
+
+    /// Returns the name of the peer.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub(crate) fn record(&self, span: &Span) {
+        span.add_properties(|| {
+            [
+                ("network.transport", Cow::Borrowed("inproc")),
+                ("network.peer.address", self.name.clone()),
+            ]
+        });
+    }
+}
+
+#[derive(Clone)]
+pub(crate) struct PeerAddress {
+    extract: PeerAddressExtractor,
+}
+
+impl PeerAddress {
+    pub(crate) fn new(extract: PeerAddressExtractor) -> Self {
+        Self { extract }
+    }
+
+    pub(crate) fn record(&self, span: &Span, extensions: &http::Extensions) {
+        if let Some(addr) = (self.extract)(extensions) {
+            record_socket_addr(span, addr);
+        }
+    }
+}
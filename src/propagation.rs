@@ -0,0 +1,430 @@
+//! Encoding and decoding of span contexts in HTTP headers.
+//!
+//! These are the functions used by [`FastraceServerLayer`](crate::FastraceServerLayer) and
+//! [`FastraceClientLayer`](crate::FastraceClientLayer). They only depend on [`http::HeaderMap`],
+//! so they can be reused outside of tower, for example in custom transports or message queues
+//! carrying HTTP-like headers.
+//!
+//! [`extract`] and [`inject`] use the formats selected by cargo features: `w3c` (enabled by
+//! default) for [W3C Trace Context](https://www.w3.org/TR/trace-context/) and `b3` for
+//! [B3](https://github.com/openzipkin/b3-propagation). Extraction tries each enabled format in
+//! that order, and injection writes all of them. Building with `default-features = false` and
+//! `features = ["b3"]` makes B3 the only format used by both layers.
+
+use fastrace::prelude::*;
+use http::HeaderMap;
+use http::HeaderName;
+use http::HeaderValue;
+use smallvec::SmallVec;
+
+use crate::TRACEPARENT_HEADER;
+use crate::TRACESTATE_HEADER;
+
+/// The single-header B3 header name.
+pub const B3_HEADER: &str = "b3";
+/// The multi-header B3 trace id header name.
+pub const B3_TRACE_ID_HEADER: &str = "x-b3-traceid";
+/// The multi-header B3 span id header name.
+pub const B3_SPAN_ID_HEADER: &str = "x-b3-spanid";
+/// The multi-header B3 sampling decision header name.
+pub const B3_SAMPLED_HEADER: &str = "x-b3-sampled";
+/// The multi-header B3 debug flag header name.
+pub const B3_FLAGS_HEADER: &str = "x-b3-flags";
+
+/// The raw W3C propagation headers of an incoming request.
+///
+/// [`FastraceServerLayer`](crate::FastraceServerLayer) inserts this into the request extensions
+/// when the request carries a `traceparent` or `tracestate` header, so later middlewares such as
+/// access logging can read the original values without parsing the headers again. The values
+/// share their storage with the request headers.
+///
+/// ```
+/// use fastrace_tonic::propagation::PropagationHeaders;
+///
+/// fn log(request: &http::Request<()>) {
+///     if let Some(headers) = request.extensions().get::<PropagationHeaders>() {
+///         println!("traceparent: {:?}", headers.traceparent());
+///     }
+/// }
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct PropagationHeaders {
+    traceparent: Option<HeaderValue>,
+    tracestate: Option<HeaderValue>,
+}
+
+impl PropagationHeaders {
+    /// Returns the `traceparent` header, as received.
+    pub fn traceparent(&self) -> Option<&HeaderValue> {
+        self.traceparent.as_ref()
+    }
+
+    /// Returns the `tracestate` header, as received.
+    pub fn tracestate(&self) -> Option<&HeaderValue> {
+        self.tracestate.as_ref()
+    }
+
+    /// Returns `None` when neither header is present.
+    pub(crate) fn from_headers(headers: &HeaderMap) -> Option<Self> {
+        let traceparent = headers.get(TRACEPARENT_HEADER).cloned();
+        let tracestate = headers.get(TRACESTATE_HEADER).cloned();
+        (traceparent.is_some() || tracestate.is_some()).then_some(Self {
+            traceparent,
+            tracestate,
+        })
+    }
+}
+
+/// Every header read by [`extract`], whichever formats are enabled.
+pub(crate) const PROPAGATION_HEADERS: [&str; 7] = [
+    TRACEPARENT_HEADER,
+    TRACESTATE_HEADER,
+    B3_HEADER,
+    B3_TRACE_ID_HEADER,
+    B3_SPAN_ID_HEADER,
+    B3_SAMPLED_HEADER,
+    B3_FLAGS_HEADER,
+];
+
+const W3C_ENABLED: bool = cfg!(feature = "w3c") || !cfg!(feature = "b3");
+const B3_ENABLED: bool = cfg!(feature = "b3");
+
+/// Decodes a span context using the enabled formats.
+///
+/// Returns `None` when no enabled format yields a valid context.
+pub fn extract(headers: &HeaderMap) -> Option<SpanContext> {
+    extract_with(|name| headers.get(name)?.to_str().ok())
+}
+
+/// Encodes a span context using the enabled formats, replacing any existing values.
+pub fn inject(span_context: &SpanContext, headers: &mut HeaderMap) {
+    inject_with(span_context, |name, value| {
+        if let Ok(value) = HeaderValue::try_from(value) {
+            headers.insert(name, value);
+        }
+    });
+}
+
+/// Encodes a span context using the enabled formats, returning the headers to set.
+///
+/// This produces the same headers as [`inject`], for code building requests outside of tower,
+/// such as raw hyper or h2 requests, where a [`HeaderMap`] is not at hand.
+///
+/// ```
+/// use fastrace::prelude::*;
+///
+/// let span_context = SpanContext::random();
+/// for (name, value) in fastrace_tonic::propagation::encode_context_headers(&span_context) {
+///     println!("{name}: {value:?}");
+/// }
+/// ```
+pub fn encode_context_headers(
+    span_context: &SpanContext,
+) -> SmallVec<[(HeaderName, HeaderValue); 4]> {
+    let mut headers = SmallVec::new();
+    inject_with(span_context, |name, value| {
+        if let Ok(value) = HeaderValue::try_from(value) {
+            headers.push((HeaderName::from_static(name), value));
+        }
+    });
+    headers
+}
+
+/// Decodes a span context from the W3C `traceparent` header.
+///
+/// Returns `None` when the header is missing or invalid.
+pub fn extract_w3c(headers: &HeaderMap) -> Option<SpanContext> {
+    extract_w3c_with(|name| headers.get(name)?.to_str().ok())
+}
+
+/// Encodes a span context into the W3C `traceparent` header, replacing any existing value.
+pub fn inject_w3c(span_context: &SpanContext, headers: &mut HeaderMap) {
+    if let Ok(traceparent) = HeaderValue::from_str(&span_context.encode_w3c_traceparent()) {
+        headers.insert(TRACEPARENT_HEADER, traceparent);
+    }
+}
+
+/// Decodes a span context from the B3 headers.
+///
+/// Both the single `b3` header and the multi-header `x-b3-*` encodings are accepted, the
+/// former taking precedence. Returns `None` when the headers are missing or invalid.
+pub fn extract_b3(headers: &HeaderMap) -> Option<SpanContext> {
+    extract_b3_with(|name| headers.get(name)?.to_str().ok())
+}
+
+/// Encodes a span context into the multi-header `x-b3-*` headers, replacing any existing values.
+pub fn inject_b3(span_context: &SpanContext, headers: &mut HeaderMap) {
+    inject_b3_with(span_context, |name, value| {
+        if let Ok(value) = HeaderValue::try_from(value) {
+            headers.insert(name, value);
+        }
+    });
+}
+
+/// Injects a span context into gRPC metadata.
+///
+/// This writes the same entries as [`FastraceClientLayer`](crate::FastraceClientLayer), for use in
+/// manual interceptors or custom transports.
+#[cfg(feature = "tonic")]
+pub fn inject_into_metadata(
+    span_context: &SpanContext,
+    metadata: &mut tonic::metadata::MetadataMap,
+) {
+    inject_with(span_context, |name, value| {
+        if let Ok(value) = value.parse() {
+            metadata.insert(name, value);
+        }
+    });
+}
+
+/// Extracts a span context from gRPC metadata.
+///
+/// This decodes the same entries as the default extractor of
+/// [`FastraceServerLayer`](crate::FastraceServerLayer). Unlike the layer, it returns `None` instead
+/// of starting a new trace when the entries are missing or invalid.
+#[cfg(feature = "tonic")]
+pub fn extract_from_metadata(metadata: &tonic::metadata::MetadataMap) -> Option<SpanContext> {
+    extract_with(|name| metadata.get(name)?.to_str().ok())
+}
+
+/// Like [`extract`], reading header values through `get`.
+pub(crate) fn extract_with<'a>(
+    get: impl Fn(&'static str) -> Option<&'a str>,
+) -> Option<SpanContext> {
+    if W3C_ENABLED {
+        if let Some(span_context) = extract_w3c_with(&get) {
+            return Some(span_context);
+        }
+    }
+    if B3_ENABLED {
+        if let Some(span_context) = extract_b3_with(&get) {
+            return Some(span_context);
+        }
+    }
+    None
+}
+
+/// Like [`inject`], writing header values through `set`.
+pub(crate) fn inject_with(span_context: &SpanContext, mut set: impl FnMut(&'static str, String)) {
+    if W3C_ENABLED {
+        set(TRACEPARENT_HEADER, span_context.encode_w3c_traceparent());
+    }
+    if B3_ENABLED {
+        inject_b3_with(span_context, &mut set);
+    }
+}
+
+fn extract_w3c_with<'a>(get: impl Fn(&'static str) -> Option<&'a str>) -> Option<SpanContext> {
+    SpanContext::decode_w3c_traceparent(get(TRACEPARENT_HEADER)?)
+}
+
+fn extract_b3_with<'a>(get: impl Fn(&'static str) -> Option<&'a str>) -> Option<SpanContext> {
+    if let Some(b3) = get(B3_HEADER) {
+        return decode_b3_single(b3);
+    }
+
+    let trace_id = decode_b3_trace_id(get(B3_TRACE_ID_HEADER)?)?;
+    let span_id = decode_b3_span_id(get(B3_SPAN_ID_HEADER)?)?;
+    let debug = get(B3_FLAGS_HEADER) == Some("1");
+    let sampled = match get(B3_SAMPLED_HEADER) {
+        Some(sampled) => sampled == "1" || sampled.eq_ignore_ascii_case("true"),
+        None => true,
+    };
+
+    let mut span_context = SpanContext::new(trace_id, span_id);
+    span_context.sampled = debug || sampled;
+    Some(span_context)
+}
+
+fn inject_b3_with(span_context: &SpanContext, mut set: impl FnMut(&'static str, String)) {
+    set(
+        B3_TRACE_ID_HEADER,
+        format!("{:032x}", span_context.trace_id.0),
+    );
+    set(
+        B3_SPAN_ID_HEADER,
+        format!("{:016x}", span_context.span_id.0),
+    );
+    set(
+        B3_SAMPLED_HEADER,
+        if span_context.sampled { "1" } else { "0" }.to_string(),
+    );
+}
+
+/// Decodes `{trace id}-{span id}[-{sampling state}[-{parent span id}]]`.
+fn decode_b3_single(b3: &str) -> Option<SpanContext> {
+    let mut fields = b3.split('-');
+    let trace_id = decode_b3_trace_id(fields.next()?)?;
+    let span_id = decode_b3_span_id(fields.next()?)?;
+    let sampled = match fields.next() {
+        Some("0") => false,
+        Some("1" | "d") | None => true,
+        Some(_) => return None,
+    };
+
+    let mut span_context = SpanContext::new(trace_id, span_id);
+    span_context.sampled = sampled;
+    Some(span_context)
+}
+
+fn decode_b3_trace_id(trace_id: &str) -> Option<TraceId> {
+    if (trace_id.len() != 16 && trace_id.len() != 32) || !is_hex(trace_id) {
+        return None;
+    }
+    match u128::from_str_radix(trace_id, 16) {
+        Ok(0) | Err(_) => None,
+        Ok(trace_id) => Some(TraceId(trace_id)),
+    }
+}
+
+fn decode_b3_span_id(span_id: &str) -> Option<SpanId> {
+    if span_id.len() != 16 || !is_hex(span_id) {
+        return None;
+    }
+    match u64::from_str_radix(span_id, 16) {
+        Ok(0) | Err(_) => None,
+        Ok(span_id) => Some(SpanId(span_id)),
+    }
+}
+
+// `from_str_radix` also accepts a leading `+`.
+fn is_hex(id: &str) -> bool {
+    id.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: &str = "80f198ee56343ba864fe8b2a57d3eff7";
+    const SPAN_ID: &str = "e457b5a2e4d86bd1";
+
+    fn multi(headers: &[(&'static str, &str)]) -> Option<SpanContext> {
+        extract_b3_with(|name| {
+            headers
+                .iter()
+                .find(|(header, _)| *header == name)
+                .map(|(_, value)| *value)
+        })
+    }
+
+    fn single(b3: &str) -> Option<SpanContext> {
+        multi(&[(B3_HEADER, b3)])
+    }
+
+    fn assert_context(span_context: Option<SpanContext>, trace_id: u128, sampled: bool) {
+        let span_context = span_context.expect("valid context");
+        assert_eq!(span_context.trace_id, TraceId(trace_id));
+        assert_eq!(span_context.span_id, SpanId(0xe457b5a2e4d86bd1));
+        assert_eq!(span_context.sampled, sampled);
+    }
+
+    #[test]
+    fn decodes_multi_header() {
+        let trace_id = 0x80f198ee56343ba864fe8b2a57d3eff7;
+        let headers = |sampled| {
+            multi(&[
+                (B3_TRACE_ID_HEADER, TRACE_ID),
+                (B3_SPAN_ID_HEADER, SPAN_ID),
+                (B3_SAMPLED_HEADER, sampled),
+            ])
+        };
+        assert_context(headers("1"), trace_id, true);
+        assert_context(headers("true"), trace_id, true);
+        assert_context(headers("0"), trace_id, false);
+
+        // A missing sampling decision defers to the receiver, which records.
+        let headers = multi(&[(B3_TRACE_ID_HEADER, TRACE_ID), (B3_SPAN_ID_HEADER, SPAN_ID)]);
+        assert_context(headers, trace_id, true);
+
+        // The debug flag implies sampling.
+        let headers = multi(&[
+            (B3_TRACE_ID_HEADER, TRACE_ID),
+            (B3_SPAN_ID_HEADER, SPAN_ID),
+            (B3_SAMPLED_HEADER, "0"),
+            (B3_FLAGS_HEADER, "1"),
+        ]);
+        assert_context(headers, trace_id, true);
+    }
+
+    #[test]
+    fn decodes_64_bit_trace_id() {
+        let headers = multi(&[
+            (B3_TRACE_ID_HEADER, "64fe8b2a57d3eff7"),
+            (B3_SPAN_ID_HEADER, SPAN_ID),
+        ]);
+        assert_context(headers, 0x64fe8b2a57d3eff7, true);
+    }
+
+    #[test]
+    fn decodes_single_header() {
+        let trace_id = 0x80f198ee56343ba864fe8b2a57d3eff7;
+        assert_context(single(&format!("{TRACE_ID}-{SPAN_ID}")), trace_id, true);
+        assert_context(single(&format!("{TRACE_ID}-{SPAN_ID}-1")), trace_id, true);
+        assert_context(single(&format!("{TRACE_ID}-{SPAN_ID}-d")), trace_id, true);
+        assert_context(single(&format!("{TRACE_ID}-{SPAN_ID}-0")), trace_id, false);
+        assert_context(
+            single(&format!("{TRACE_ID}-{SPAN_ID}-1-05e3ac9a4f6e3b90")),
+            trace_id,
+            true,
+        );
+        assert!(single(&format!("{TRACE_ID}-{SPAN_ID}-x")).is_none());
+    }
+
+    #[test]
+    fn single_header_takes_precedence() {
+        let headers = multi(&[
+            (B3_HEADER, format!("{TRACE_ID}-{SPAN_ID}-0").as_str()),
+            (B3_TRACE_ID_HEADER, "0000000000000000000000000000abcd"),
+            (B3_SPAN_ID_HEADER, "000000000000abcd"),
+            (B3_SAMPLED_HEADER, "1"),
+        ]);
+        assert_context(headers, 0x80f198ee56343ba864fe8b2a57d3eff7, false);
+
+        // An invalid single header is not completed by the multi-header encoding.
+        let headers = multi(&[
+            (B3_HEADER, "invalid"),
+            (B3_TRACE_ID_HEADER, TRACE_ID),
+            (B3_SPAN_ID_HEADER, SPAN_ID),
+        ]);
+        assert!(headers.is_none());
+    }
+
+    #[test]
+    fn rejects_invalid_ids() {
+        for (trace_id, span_id) in [
+            ("80f198ee56343ba864fe8b2a57d3eff", SPAN_ID),
+            (TRACE_ID, "e457b5a2e4d86bd"),
+            ("00000000000000000000000000000000", SPAN_ID),
+            (TRACE_ID, "0000000000000000"),
+            ("+0f198ee56343ba864fe8b2a57d3eff7", SPAN_ID),
+            (TRACE_ID, "+457b5a2e4d86bd1"),
+            ("80f198ee56343ba864fe8b2a57d3effg", SPAN_ID),
+        ] {
+            let headers = multi(&[(B3_TRACE_ID_HEADER, trace_id), (B3_SPAN_ID_HEADER, span_id)]);
+            assert!(headers.is_none(), "{trace_id}-{span_id}");
+            assert!(single(&format!("{trace_id}-{span_id}")).is_none());
+        }
+        assert!(multi(&[(B3_TRACE_ID_HEADER, TRACE_ID)]).is_none());
+        assert!(single(TRACE_ID).is_none());
+    }
+
+    #[test]
+    fn b3_round_trips() {
+        let mut span_context = SpanContext::new(TraceId(0x1234), SpanId(0x5678));
+        span_context.sampled = false;
+        let mut headers = HeaderMap::new();
+        inject_b3(&span_context, &mut headers);
+
+        assert_eq!(
+            headers[B3_TRACE_ID_HEADER],
+            "00000000000000000000000000001234"
+        );
+        assert_eq!(headers[B3_SPAN_ID_HEADER], "0000000000005678");
+        assert_eq!(headers[B3_SAMPLED_HEADER], "0");
+        let decoded = extract_b3(&headers).unwrap();
+        assert_eq!(decoded.trace_id, span_context.trace_id);
+        assert_eq!(decoded.span_id, span_context.span_id);
+        assert!(!decoded.sampled);
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::time::Duration;
+use std::time::Instant;
+
+use fastrace::collector::Reporter;
+use fastrace::collector::SpanRecord;
+use fastrace::prelude::*;
+
+use crate::sampling::RateLimiter;
+
+/// A reporter wrapper recording how many spans were created under each root span.
+///
+/// fastrace submits all spans of a root span, such as the one created by
+/// [`FastraceServerLayer`](crate::FastraceServerLayer) for a request, in the same batch. This
+/// reporter counts the descendants of every root span in a batch and records the result as the
+/// `child_span_count` property of the root before handing the batch to the inner reporter. It
+/// helps finding endpoints with pathological span explosion.
+///
+/// ```
+/// use fastrace::collector::Config;
+/// use fastrace::collector::ConsoleReporter;
+/// use fastrace_tonic::ChildSpanCountReporter;
+///
+/// fastrace::set_reporter(
+///     ChildSpanCountReporter::new(ConsoleReporter),
+///     Config::default(),
+/// );
+/// ```
+pub struct ChildSpanCountReporter<R> {
+    inner: R,
+}
+
+impl<R> ChildSpanCountReporter<R> {
+    /// Wraps `inner`, which receives the spans once counted.
+    pub fn new(inner: R) -> Self {
+        Self { inner }
+    }
+}
+
+impl<R: Reporter> Reporter for ChildSpanCountReporter<R> {
+    fn report(&mut self, mut spans: Vec<SpanRecord>) {
+        let parents: HashMap<SpanId, SpanId> = spans
+            .iter()
+            .map(|span| (span.span_id, span.parent_id))
+            .collect();
+
+        let mut counts: HashMap<SpanId, usize> = HashMap::new();
+        for span in &spans {
+            if parents.contains_key(&span.parent_id) {
+                let root = root_of(&parents, span.span_id);
+                *counts.entry(root).or_default() += 1;
+            }
+        }
+
+        for span in &mut spans {
+            if !parents.contains_key(&span.parent_id) {
+                let count = counts.get(&span.span_id).copied().unwrap_or_default();
+                span.properties
+                    .push(("child_span_count".into(), count.to_string().into()));
+            }
+        }
+
+        self.inner.report(spans);
+    }
+}
+
+/// Follows the parent links within the batch up to the span whose parent is not in the batch.
+fn root_of(parents: &HashMap<SpanId, SpanId>, mut span_id: SpanId) -> SpanId {
+    // Bounded by the batch size in case of a cycle.
+    for _ in 0..parents.len() {
+        match parents.get(&span_id) {
+            Some(parent_id) if parents.contains_key(parent_id) => span_id = *parent_id,
+            _ => break,
+        }
+    }
+    span_id
+}
+
+/// A reporter wrapper retaining at most a given number of traces per second, preferring
+/// high-priority and failed requests.
+///
+/// Instead of dropping traces uniformly at random when the budget is exhausted, this reporter
+/// first keeps the traces of each batch having a span marked by
+/// [`ServerTracing::with_priority_header`](crate::ServerTracing::with_priority_header), with a
+/// `sampling.priority` property set to `high` or an `error` property set to `true`, and then
+/// fills the remaining budget with the other traces in the order they were reported.
+///
+/// The spans of a trace may be reported over several batches, such as the spans of a request and
+/// of a `slow request` reported while it was in flight. The decision made for the first batch of a
+/// trace is remembered for a minute after its spans were last seen and applied to the later
+/// batches, without taking from the budget, so traces are kept or dropped as a whole.
+///
+/// ```
+/// use fastrace::collector::Config;
+/// use fastrace::collector::ConsoleReporter;
+/// use fastrace_tonic::PriorityRetentionReporter;
+///
+/// fastrace::set_reporter(
+///     PriorityRetentionReporter::new(ConsoleReporter, 100),
+///     Config::default(),
+/// );
+/// ```
+pub struct PriorityRetentionReporter<R> {
+    inner: R,
+    budget: RateLimiter,
+    /// Whether each recently seen trace is retained, and when its spans were last seen.
+    decisions: HashMap<TraceId, (bool, Instant)>,
+    last_expiry: Instant,
+}
+
+/// How long the decision for a trace is remembered after its spans were last seen.
+const DECISION_TTL: Duration = Duration::from_secs(60);
+
+impl<R> PriorityRetentionReporter<R> {
+    /// Wraps `inner`, which receives at most `per_second` traces per second.
+    pub fn new(inner: R, per_second: u32) -> Self {
+        Self {
+            inner,
+            budget: RateLimiter::new(per_second),
+            decisions: HashMap::new(),
+            last_expiry: Instant::now(),
+        }
+    }
+}
+
+impl<R: Reporter> Reporter for PriorityRetentionReporter<R> {
+    fn report(&mut self, spans: Vec<SpanRecord>) {
+        let now = Instant::now();
+        if now.duration_since(self.last_expiry) >= DECISION_TTL / 4 {
+            self.last_expiry = now;
+            self.decisions
+                .retain(|_, (_, last_seen)| now.duration_since(*last_seen) < DECISION_TTL);
+        }
+
+        // The new traces of the batch in the order they were reported, and their index by id.
+        let mut traces: Vec<(TraceId, bool)> = Vec::new();
+        let mut indices: HashMap<TraceId, usize> = HashMap::new();
+        for span in &spans {
+            if let Some((_, last_seen)) = self.decisions.get_mut(&span.trace_id) {
+                *last_seen = now;
+                continue;
+            }
+            let preferred = is_preferred(span);
+            match indices.entry(span.trace_id) {
+                Entry::Occupied(index) => traces[*index.get()].1 |= preferred,
+                Entry::Vacant(index) => {
+                    index.insert(traces.len());
+                    traces.push((span.trace_id, preferred));
+                }
+            }
+        }
+
+        for preferred in [true, false] {
+            for (trace_id, trace_preferred) in &traces {
+                if *trace_preferred == preferred {
+                    let retained = self.budget.try_acquire();
+                    self.decisions.insert(*trace_id, (retained, now));
+                }
+            }
+        }
+
+        let spans: Vec<SpanRecord> = spans
+            .into_iter()
+            .filter(|span| {
+                self.decisions
+                    .get(&span.trace_id)
+                    .is_some_and(|(retained, _)| *retained)
+            })
+            .collect();
+        if !spans.is_empty() {
+            self.inner.report(spans);
+        }
+    }
+}
+
+fn is_preferred(span: &SpanRecord) -> bool {
+    span.properties.iter().any(|(key, value)| {
+        (key == "sampling.priority" && value == "high") || (key == "error" && value == "true")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Records the trace ids of the reported spans.
+    #[derive(Clone, Default)]
+    struct Capture {
+        traces: Arc<Mutex<Vec<TraceId>>>,
+    }
+
+    impl Reporter for Capture {
+        fn report(&mut self, spans: Vec<SpanRecord>) {
+            let mut traces = self.traces.lock().unwrap();
+            traces.extend(spans.iter().map(|span| span.trace_id));
+        }
+    }
+
+    fn span(trace_id: u128, properties: &[(&'static str, &'static str)]) -> SpanRecord {
+        SpanRecord {
+            trace_id: TraceId(trace_id),
+            properties: properties
+                .iter()
+                .map(|(key, value)| ((*key).into(), (*value).into()))
+                .collect(),
+            ..SpanRecord::default()
+        }
+    }
+
+    fn take(capture: &Capture) -> Vec<TraceId> {
+        std::mem::take(&mut *capture.traces.lock().unwrap())
+    }
+
+    #[test]
+    fn prefers_failed_traces() {
+        let capture = Capture::default();
+        let mut reporter = PriorityRetentionReporter::new(capture.clone(), 1);
+
+        reporter.report(vec![span(1, &[]), span(2, &[("error", "true")])]);
+        assert_eq!(take(&capture), [TraceId(2)]);
+    }
+
+    #[test]
+    fn later_batches_follow_the_first_decision() {
+        let capture = Capture::default();
+        let mut reporter = PriorityRetentionReporter::new(capture.clone(), 1);
+
+        reporter.report(vec![span(1, &[])]);
+        reporter.report(vec![span(2, &[])]);
+        assert_eq!(take(&capture), [TraceId(1)]);
+
+        // The budget is exhausted, but trace 1 was retained and trace 2 dropped.
+        reporter.report(vec![span(2, &[("error", "true")]), span(1, &[])]);
+        assert_eq!(take(&capture), [TraceId(1)]);
+    }
+}
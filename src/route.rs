@@ -0,0 +1,213 @@
+use std::borrow::Cow;
+
+use fastrace::prelude::*;
+
+use crate::capture;
+
+/// A pattern matched against the path of an incoming request, such as `/pkg.Service/Method`.
+#[non_exhaustive]
+#[derive(Clone, Debug)]
+pub enum RoutePattern {
+    /// Matches the path exactly.
+    Exact(String),
+    /// Matches any path starting with the given prefix.
+    Prefix(String),
+    /// Matches the path against a glob, where `*` matches any sequence of characters
+    /// (including `/`) and `?` matches a single character.
+    Glob(String),
+}
+
+impl RoutePattern {
+    /// Creates a pattern matching the path exactly.
+    pub fn exact(path: impl Into<String>) -> Self {
+        Self::Exact(path.into())
+    }
+
+    /// Creates a pattern matching paths starting with `prefix`.
+    pub fn prefix(prefix: impl Into<String>) -> Self {
+        Self::Prefix(prefix.into())
+    }
+
+    /// Creates a pattern matching paths against a glob.
+    pub fn glob(glob: impl Into<String>) -> Self {
+        Self::Glob(glob.into())
+    }
+
+    /// Returns whether `path` matches this pattern.
+    pub fn matches(&self, path: &str) -> bool {
+        match self {
+            Self::Exact(exact) => path == exact,
+            Self::Prefix(prefix) => path.starts_with(prefix.as_str()),
+            Self::Glob(glob) => glob_match(glob, path),
+        }
+    }
+}
+
+/// Tracing settings applied to requests matching a [`RoutePattern`].
+///
+/// Routes are configured with
+/// [`ServerTracing::with_route`](crate::ServerTracing::with_route). Settings left unset
+/// fall back to the layer's behavior.
+#[derive(Clone, Debug, Default)]
+pub struct RouteConfig {
+    pub(crate) skip: bool,
+    pub(crate) span_name: Option<Cow<'static, str>>,
+    pub(crate) sample_ratio: Option<f64>,
+    pub(crate) properties: Vec<(Cow<'static, str>, Cow<'static, str>)>,
+    pub(crate) captured_headers: Vec<http::HeaderName>,
+}
+
+impl RouteConfig {
+    /// Creates a route configuration that keeps the layer's behavior.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Skip tracing for matching requests, as if rejected by a filter.
+    pub fn skip(mut self) -> Self {
+        self.skip = true;
+        self
+    }
+
+    /// Use a fixed span name instead of the request URI.
+    pub fn with_span_name(mut self, name: impl Into<Cow<'static, str>>) -> Self {
+        self.span_name = Some(name.into());
+        self
+    }
+
+    /// Record only a fraction of the matching requests that start a new trace.
+    ///
+    /// This overrides
+    /// [`ServerTracing::with_sample_ratio`](crate::ServerTracing::with_sample_ratio)
+    /// for matching requests.
+    pub fn with_sample_ratio(mut self, ratio: f64) -> Self {
+        self.sample_ratio = Some(ratio);
+        self
+    }
+
+    /// Attach a fixed property to the span of matching requests.
+    pub fn with_property(
+        mut self,
+        key: impl Into<Cow<'static, str>>,
+        value: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        self.properties.push((key.into(), value.into()));
+        self
+    }
+
+    /// Record the value of a request header as a span property, keyed by the header name.
+    pub fn with_captured_header(mut self, name: http::HeaderName) -> Self {
+        self.captured_headers.push(name);
+        self
+    }
+
+    pub(crate) fn record(
+        &self,
+        span: &Span,
+        headers: &http::HeaderMap,
+        redactor: Option<&capture::Redactor>,
+    ) {
+        for (key, value) in &self.properties {
+            span.add_property(|| (key.clone(), value.clone()));
+        }
+        for name in &self.captured_headers {
+            if let Some(value) = headers.get(name).and_then(|value| value.to_str().ok()) {
+                capture::add_property(span, redactor, name.as_str().to_owned(), value);
+            }
+        }
+    }
+}
+
+pub(crate) fn find_route<'a>(
+    routes: &'a [(RoutePattern, RouteConfig)],
+    path: &str,
+) -> Option<&'a RouteConfig> {
+    routes
+        .iter()
+        .find(|(pattern, _)| pattern.matches(path))
+        .map(|(_, config)| config)
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    // Byte offsets, always on a character boundary.
+    let (mut p, mut t) = (0, 0);
+    // Position of the last `*` in the pattern and the text position it is currently matched up to.
+    let mut backtrack = None;
+
+    while let Some(c) = text[t..].chars().next() {
+        match pattern[p..].chars().next() {
+            Some('*') => {
+                backtrack = Some((p, t));
+                p += 1;
+            }
+            Some(pc) if pc == '?' || pc == c => {
+                p += pc.len_utf8();
+                t += c.len_utf8();
+            }
+            _ => {
+                let Some((star, matched)) = backtrack else {
+                    return false;
+                };
+                // Let the last `*` swallow one more character and retry.
+                let swallowed = text[matched..].chars().next().map_or(1, char::len_utf8);
+                backtrack = Some((star, matched + swallowed));
+                p = star + 1;
+                t = matched + swallowed;
+            }
+        }
+    }
+
+    pattern[p..].chars().all(|c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_wildcards() {
+        assert!(glob_match("/pkg.Service/*", "/pkg.Service/Method"));
+        assert!(glob_match("*/Method", "/pkg.Service/Method"));
+        assert!(glob_match("/pkg.*/Get*", "/pkg.Service/GetUser"));
+        assert!(glob_match("/v?/users", "/v1/users"));
+        assert!(glob_match("*", ""));
+        assert!(!glob_match("/pkg.Service/*", "/pkg.Other/Method"));
+        assert!(!glob_match("/v?/users", "/v10/users"));
+        assert!(!glob_match("?", ""));
+    }
+
+    #[test]
+    fn glob_question_mark_matches_one_character() {
+        assert!(glob_match("/caf?", "/café"));
+        assert!(glob_match("/?/x", "/日/x"));
+        assert!(!glob_match("/??/x", "/日/x"));
+        assert!(glob_match("*?x", "日x"));
+    }
+
+    #[test]
+    fn glob_star_in_path() {
+        assert!(glob_match("*a", "*ba"));
+        assert!(glob_match("/日本*", "/日本語"));
+    }
+
+    #[test]
+    fn first_matching_route_wins() {
+        let routes = [
+            (RoutePattern::exact("/health"), RouteConfig::new().skip()),
+            (
+                RoutePattern::prefix("/pkg."),
+                RouteConfig::new().with_span_name("pkg"),
+            ),
+            (RoutePattern::glob("*"), RouteConfig::new()),
+        ];
+        assert!(find_route(&routes, "/health").unwrap().skip);
+        assert_eq!(
+            find_route(&routes, "/pkg.Service/Method")
+                .unwrap()
+                .span_name
+                .as_deref(),
+            Some("pkg")
+        );
+        assert!(find_route(&routes[..2], "/other").is_none());
+    }
+}
@@ -0,0 +1,116 @@
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::time::Instant;
+
+use fastrace::prelude::*;
+
+/// Returns whether the trace should be recorded under the given ratio.
+///
+/// Like OpenTelemetry's `TraceIdRatioBased` sampler, this compares the lower 64 bits of the
+/// trace id against the ratio, so the decision is consistent for all spans of a trace.
+pub(crate) fn sampled_by_ratio(trace_id: TraceId, ratio: f64) -> bool {
+    if ratio >= 1.0 {
+        return true;
+    }
+    if ratio <= 0.0 {
+        return false;
+    }
+    let threshold = (ratio * u64::MAX as f64) as u64;
+    (trace_id.0 as u64) < threshold
+}
+
+/// A token bucket limiting how many traces are recorded per second.
+pub(crate) struct RateLimiter {
+    per_second: f64,
+    bucket: Mutex<TokenBucket>,
+}
+
+struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(per_second: u32) -> Self {
+        let per_second = per_second as f64;
+        Self {
+            per_second,
+            bucket: Mutex::new(TokenBucket {
+                tokens: per_second,
+                last_refill: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes a token from the bucket, returning `false` when it is empty.
+    pub(crate) fn try_acquire(&self) -> bool {
+        let mut bucket = self.bucket.lock().unwrap_or_else(PoisonError::into_inner);
+
+        let now = Instant::now();
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.per_second).min(self.per_second);
+        bucket.last_refill = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn ratio_bounds() {
+        let trace_id = TraceId(u128::MAX);
+        assert!(sampled_by_ratio(trace_id, 1.0));
+        assert!(!sampled_by_ratio(TraceId(0), 0.0));
+        assert!(sampled_by_ratio(TraceId(0), 0.5));
+        assert!(!sampled_by_ratio(TraceId(u64::MAX as u128), 0.5));
+        // Only the lower 64 bits are compared.
+        assert!(sampled_by_ratio(TraceId(u128::MAX << 64), 0.5));
+    }
+
+    #[test]
+    fn bucket_starts_full_and_empties() {
+        let limiter = RateLimiter::new(3);
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+    }
+
+    #[test]
+    fn bucket_refills_up_to_capacity() {
+        let limiter = RateLimiter::new(2);
+        while limiter.try_acquire() {}
+
+        let rewind = |elapsed| {
+            let mut bucket = limiter.bucket.lock().unwrap();
+            bucket.last_refill = Instant::now() - elapsed;
+        };
+
+        // Half a second refills one token at two per second.
+        rewind(Duration::from_millis(500));
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+
+        // A long pause refills no more than a second's worth.
+        rewind(Duration::from_secs(60));
+        assert!(limiter.try_acquire());
+        assert!(limiter.try_acquire());
+        assert!(!limiter.try_acquire());
+    }
+
+    #[test]
+    fn zero_rate_never_records() {
+        let limiter = RateLimiter::new(0);
+        assert!(!limiter.try_acquire());
+    }
+}
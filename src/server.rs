@@ -0,0 +1,2421 @@
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::future::Future;
+use std::panic;
+use std::panic::AssertUnwindSafe;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::Context;
+use std::task::Poll;
+use std::task::ready;
+use std::time::Duration;
+use std::time::Instant;
+
+use fastrace::prelude::*;
+use http::Request;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::CacheStatus;
+use crate::FastraceRequestBody;
+use crate::FastraceServerBody;
+use crate::FastraceToggle;
+use crate::ForwardedFor;
+use crate::InProcessPeer;
+use crate::RequestSpan;
+use crate::RouteConfig;
+use crate::RoutePattern;
+use crate::StatusInfo;
+use crate::TRACEPARENT_HEADER;
+use crate::TRACESTATE_HEADER;
+use crate::TraceIdInErrors;
+use crate::capture;
+use crate::carrier::Carrier;
+use crate::forwarding::BAGGAGE_HEADER;
+use crate::name;
+use crate::name::SpanNameTemplate;
+use crate::peer::PeerAddress;
+use crate::propagation;
+use crate::propagation::PropagationHeaders;
+use crate::route;
+use crate::sampling;
+use crate::socket;
+use crate::status;
+use crate::summary;
+use crate::summary::Summary;
+use crate::timeout;
+use crate::watchdog;
+
+type SpanContextExtractor =
+    Arc<dyn Fn(&http::request::Parts) -> ExtractionDecision + Send + Sync + 'static>;
+
+type RequestFilter = Arc<dyn Fn(&http::request::Parts) -> bool + Send + Sync + 'static>;
+
+type TenantExtractor = Arc<dyn Fn(&http::request::Parts) -> Option<String> + Send + Sync + 'static>;
+
+type PropertyExtractor = Arc<
+    dyn Fn(&http::request::Parts) -> Vec<(Cow<'static, str>, Cow<'static, str>)>
+        + Send
+        + Sync
+        + 'static,
+>;
+
+type SocketFdExtractor =
+    Arc<dyn Fn(&http::Extensions) -> Option<socket::SocketFd> + Send + Sync + 'static>;
+
+type SpanFactory = Arc<
+    dyn Fn(Cow<'static, str>, SpanContext, &http::request::Parts) -> Span + Send + Sync + 'static,
+>;
+
+type FailureClassifier = Arc<dyn Fn(&StatusInfo) -> bool + Send + Sync + 'static>;
+
+type InvalidContextHandler = Arc<dyn Fn(&http::HeaderValue) + Send + Sync + 'static>;
+
+#[derive(Clone)]
+struct ForceTrace {
+    header: http::HeaderName,
+    authorize: RequestFilter,
+}
+
+/// What the server layer does when an incoming request carries no usable `traceparent`.
+///
+/// This only applies to the default extraction; a custom span context extractor decides on its
+/// own by returning `None`.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum MissingContextPolicy {
+    /// Start a new trace with a random span context.
+    #[default]
+    NewTrace,
+    /// Do not trace the request.
+    Noop,
+    /// Continue the trace of the current local parent, as a child of it, and start a new trace
+    /// when there is none.
+    ///
+    /// This suits servers embedded in a process that is already traced, such as an in-process
+    /// gateway calling the server under its own span. The sampling decision of the local parent
+    /// is kept.
+    LocalParent,
+}
+
+/// What the server layer does with a request, as decided by a custom extractor configured with
+/// [`ServerTracing::with_extraction_decision`].
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExtractionDecision {
+    /// Trace the request as a child of the span context.
+    Trace(SpanContext),
+    /// Propagate the span context to downstream calls without recording the request, as if the
+    /// context was not sampled.
+    PropagateOnly(SpanContext),
+    /// Do not trace the request.
+    Ignore,
+}
+
+impl From<Option<SpanContext>> for ExtractionDecision {
+    fn from(span_context: Option<SpanContext>) -> Self {
+        match span_context {
+            Some(span_context) => Self::Trace(span_context),
+            None => Self::Ignore,
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct ServerConfig {
+    /// `None` selects the default `traceparent` extraction.
+    span_context_extractor: Option<SpanContextExtractor>,
+    missing_context_policy: MissingContextPolicy,
+    filter: Option<RequestFilter>,
+    routes: Vec<(RoutePattern, RouteConfig)>,
+    sample_ratio: Option<f64>,
+    method_sample_ratios: HashMap<String, f64>,
+    rate_limiter: Option<Arc<sampling::RateLimiter>>,
+    force_trace: Option<ForceTrace>,
+    flavor_header: Option<http::HeaderName>,
+    disable_header: Option<http::HeaderName>,
+    invalid_context_handler: Option<InvalidContextHandler>,
+    sampling_dry_run: bool,
+    trace_id_in_errors: Option<TraceIdInErrors>,
+    peer_address: Option<PeerAddress>,
+    socket_fd: Option<SocketFdExtractor>,
+    priority_header: Option<http::HeaderName>,
+    span_name_template: Option<SpanNameTemplate>,
+    max_span_name_len: Option<usize>,
+    normalize_uri: bool,
+    toggle: Option<FastraceToggle>,
+    services: HashMap<String, Arc<ServerConfig>>,
+    summary: Option<Arc<Summary>>,
+    grpc_only: bool,
+    content_types: Vec<(String, Arc<ServerConfig>)>,
+    fan_in_header: Option<http::HeaderName>,
+    span_factory: Option<SpanFactory>,
+    otel_semconv: bool,
+    #[cfg(feature = "mtls")]
+    client_identity: bool,
+    user_agent: bool,
+    compression: bool,
+    content_length: bool,
+    failure_classifier: Option<FailureClassifier>,
+    /// Lowercase header names and the properties they are recorded as.
+    captured_request_headers: Vec<(String, String)>,
+    captured_response_headers: Vec<(String, String)>,
+    redactor: Option<capture::Redactor>,
+    request_id_header: Option<http::HeaderName>,
+    forwarded_for: Option<ForwardedFor>,
+    tenant: Option<TenantExtractor>,
+    properties: Option<PropertyExtractor>,
+    #[cfg(feature = "prost-types")]
+    status_details: bool,
+    pub(crate) response_body_span: bool,
+    pub(crate) message_events: bool,
+    message_counts: bool,
+    body_bytes: bool,
+    pub(crate) time_to_first_byte: bool,
+    watchdog: Option<Duration>,
+    poll_timing: bool,
+    poll_events: bool,
+    backpressure: bool,
+    context_forwarding: bool,
+}
+
+/// Builder for [`FastraceServerLayer`].
+///
+/// All options have sensible defaults, so `ServerTracing::new().build()` is equivalent to
+/// `FastraceServerLayer::default()`.
+///
+/// ```
+/// use fastrace_tonic::MissingContextPolicy;
+/// use fastrace_tonic::ServerTracing;
+///
+/// let layer = ServerTracing::new()
+///     .with_filter(fastrace_tonic::exclude_health_and_reflection)
+///     .with_missing_context_policy(MissingContextPolicy::NewTrace)
+///     .with_sample_ratio(0.1)
+///     .build();
+/// ```
+#[derive(Clone, Default)]
+pub struct ServerTracing {
+    config: ServerConfig,
+}
+
+impl ServerTracing {
+    /// Creates a builder with the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Configure a custom span context extractor.
+    ///
+    /// Return `None` to keep the span as noop.
+    pub fn with_span_context_extractor<F>(mut self, f: F) -> Self
+    where F: Fn(&http::HeaderMap) -> Option<SpanContext> + Send + Sync + 'static {
+        self.config.span_context_extractor = Some(Arc::new(move |parts| f(&parts.headers).into()));
+        self
+    }
+
+    /// Configure a custom span context extractor receiving the whole request head.
+    ///
+    /// Unlike [`ServerTracing::with_span_context_extractor`], the extractor can inspect the URI,
+    /// method and extensions, such as tonic's connect info, to decide how to derive the parent
+    /// context. Return `None` to keep the span as noop.
+    ///
+    /// ```
+    /// use fastrace_tonic::ServerTracing;
+    ///
+    /// let layer = ServerTracing::new()
+    ///     .with_span_context_extractor_from_parts(|parts| {
+    ///         if parts.uri.path().starts_with("/internal.") {
+    ///             return None;
+    ///         }
+    ///         fastrace_tonic::propagation::extract(&parts.headers)
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn with_span_context_extractor_from_parts<F>(mut self, f: F) -> Self
+    where F: Fn(&http::request::Parts) -> Option<SpanContext> + Send + Sync + 'static {
+        self.config.span_context_extractor = Some(Arc::new(move |parts| f(parts).into()));
+        self
+    }
+
+    /// Configure a custom extractor deciding both the parent of the request span and whether
+    /// the request is recorded at all.
+    ///
+    /// Unlike [`ServerTracing::with_span_context_extractor_from_parts`], the extractor can
+    /// trust a context for propagation to downstream calls while not recording the request,
+    /// see [`ExtractionDecision`].
+    ///
+    /// ```
+    /// use fastrace_tonic::ExtractionDecision;
+    /// use fastrace_tonic::ServerTracing;
+    ///
+    /// let layer = ServerTracing::new()
+    ///     .with_extraction_decision(|parts| {
+    ///         match fastrace_tonic::propagation::extract(&parts.headers) {
+    ///             Some(parent) if parts.uri.path().starts_with("/internal.") => {
+    ///                 ExtractionDecision::PropagateOnly(parent)
+    ///             }
+    ///             Some(parent) => ExtractionDecision::Trace(parent),
+    ///             None => ExtractionDecision::Ignore,
+    ///         }
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn with_extraction_decision<F>(mut self, f: F) -> Self
+    where F: Fn(&http::request::Parts) -> ExtractionDecision + Send + Sync + 'static {
+        self.config.span_context_extractor = Some(Arc::new(f));
+        self
+    }
+
+    /// Configure what happens when a request carries no usable `traceparent`.
+    ///
+    /// Defaults to [`MissingContextPolicy::NewTrace`].
+    pub fn with_missing_context_policy(mut self, policy: MissingContextPolicy) -> Self {
+        self.config.missing_context_policy = policy;
+        self
+    }
+
+    /// Use a separate configuration for requests to a gRPC service.
+    ///
+    /// `service` is the full service name, such as `shop.Checkout`. Requests to that service
+    /// are handled entirely by `tracing`, as if it was the only configuration, which allows a
+    /// server hosting several services to trace each one differently with a single layer.
+    /// Alternatively, with the `tonic` feature, a layer can be applied to a single service with
+    /// `Server::builder().add_service(layer.layer(service))`.
+    ///
+    /// ```
+    /// use fastrace_tonic::ServerTracing;
+    ///
+    /// let layer = ServerTracing::new()
+    ///     .with_sample_ratio(0.01)
+    ///     .with_service("shop.Checkout", ServerTracing::new().with_sample_ratio(1.0))
+    ///     .build();
+    /// ```
+    pub fn with_service(mut self, service: impl Into<String>, tracing: ServerTracing) -> Self {
+        self.config
+            .services
+            .insert(service.into(), Arc::new(tracing.config));
+        self
+    }
+
+    /// Only trace gRPC requests, whose `content-type` starts with `application/grpc`.
+    ///
+    /// This is useful for servers that also serve plain HTTP routes, such as health endpoints or
+    /// an axum fallback. Other requests are passed through as if rejected by a filter.
+    pub fn with_grpc_only(mut self, enabled: bool) -> Self {
+        self.config.grpc_only = enabled;
+        self
+    }
+
+    /// Use a separate configuration for requests whose `content-type` starts with `prefix`.
+    ///
+    /// Content types are checked in the order they were added, after the services configured
+    /// with [`ServerTracing::with_service`], and the first match applies. Matching requests are
+    /// handled entirely by `tracing`.
+    ///
+    /// ```
+    /// use fastrace_tonic::ServerTracing;
+    ///
+    /// let layer = ServerTracing::new()
+    ///     .with_content_type(
+    ///         "application/json",
+    ///         ServerTracing::new().with_uri_normalization(true),
+    ///     )
+    ///     .build();
+    /// ```
+    pub fn with_content_type(mut self, prefix: impl Into<String>, tracing: ServerTracing) -> Self {
+        self.config
+            .content_types
+            .push((prefix.into(), Arc::new(tracing.config)));
+        self
+    }
+
+    /// Turn tracing on and off at runtime with `toggle`.
+    ///
+    /// While the toggle is off, requests are passed through as if rejected by a filter. See
+    /// [`FastraceToggle`].
+    pub fn with_toggle(mut self, toggle: FastraceToggle) -> Self {
+        self.config.toggle = Some(toggle);
+        self
+    }
+
+    /// Configure a filter deciding which requests are traced.
+    ///
+    /// The filter receives the request head and runs right after the
+    /// [`ServerTracing::with_toggle`] and [`ServerTracing::with_grpc_only`] checks, before
+    /// anything else. When it returns `false`, the request is passed through untouched: no span
+    /// context is extracted, no span is created, no request id is generated, and the request and
+    /// response bodies are passed through as is.
+    pub fn with_filter<F>(mut self, f: F) -> Self
+    where F: Fn(&http::request::Parts) -> bool + Send + Sync + 'static {
+        self.config.filter = Some(Arc::new(f));
+        self
+    }
+
+    /// Configure tracing settings for requests whose path matches `pattern`.
+    ///
+    /// Routes are checked in the order they were added and the first match applies.
+    ///
+    /// ```
+    /// use fastrace_tonic::RouteConfig;
+    /// use fastrace_tonic::RoutePattern;
+    /// use fastrace_tonic::ServerTracing;
+    ///
+    /// let layer = ServerTracing::new()
+    ///     .with_route(
+    ///         RoutePattern::exact("/shop.Checkout/Pay"),
+    ///         RouteConfig::new().with_span_name("Checkout/Pay"),
+    ///     )
+    ///     .with_route(
+    ///         RoutePattern::glob("/shop.Feed/*"),
+    ///         RouteConfig::new().with_sample_ratio(0.01),
+    ///     )
+    ///     .with_route(
+    ///         RoutePattern::prefix("/internal."),
+    ///         RouteConfig::new().skip(),
+    ///     )
+    ///     .build();
+    /// ```
+    pub fn with_route(mut self, pattern: RoutePattern, config: RouteConfig) -> Self {
+        self.config.routes.push((pattern, config));
+        self
+    }
+
+    /// Record only a fraction of the requests that start a new trace.
+    ///
+    /// Requests carrying a `traceparent` keep the sampling decision made upstream. For the others,
+    /// the decision is derived from the trace id of the new context; unrecorded requests still
+    /// propagate their context to downstream calls. A custom extractor is expected to make its
+    /// own decision through the `sampled` flag of the returned context.
+    ///
+    /// The ratio is clamped to `0.0..=1.0`.
+    pub fn with_sample_ratio(mut self, ratio: f64) -> Self {
+        self.config.sample_ratio = Some(ratio);
+        self
+    }
+
+    /// Record only a fraction of the requests to a gRPC method that start a new trace.
+    ///
+    /// `method` is the full method name, such as `shop.Checkout/Pay`. This overrides
+    /// [`ServerTracing::with_sample_ratio`] for that method, and is overridden by a matching
+    /// route's sample ratio.
+    ///
+    /// ```
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_method_sample_ratio("shop.Checkout/Pay", 1.0)
+    ///     .with_method_sample_ratio("shop.Feed/List", 0.01)
+    ///     .build();
+    /// ```
+    pub fn with_method_sample_ratio(mut self, method: impl Into<String>, ratio: f64) -> Self {
+        self.config
+            .method_sample_ratios
+            .insert(method.into(), ratio);
+        self
+    }
+
+    /// Record at most `per_second` root spans per second.
+    ///
+    /// This caps the load on the collector during traffic spikes. Requests over the limit are
+    /// handled as if they were not sampled: no span is recorded, but their context is still
+    /// propagated to downstream calls. The limit applies after all sampling decisions, including
+    /// the ones made upstream.
+    pub fn with_rate_limit(mut self, per_second: u32) -> Self {
+        self.config.rate_limiter = Some(Arc::new(sampling::RateLimiter::new(per_second)));
+        self
+    }
+
+    /// Always record requests carrying the `header` debug header, bypassing every sampling
+    /// decision.
+    ///
+    /// `authorize` is only called for requests carrying the header and must return `true` for
+    /// the request to be force-traced, for example after checking a shared secret in the header
+    /// or the peer address. Forced requests get a `force_trace` property. Filters and skipped
+    /// routes still apply.
+    ///
+    /// ```
+    /// use fastrace_tonic::FORCE_TRACE_HEADER;
+    /// use http::HeaderName;
+    ///
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_sample_ratio(0.01)
+    ///     .with_force_trace(HeaderName::from_static(FORCE_TRACE_HEADER), |parts| {
+    ///         parts.headers[FORCE_TRACE_HEADER] == "let-me-in"
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn with_force_trace<F>(mut self, header: http::HeaderName, authorize: F) -> Self
+    where F: Fn(&http::request::Parts) -> bool + Send + Sync + 'static {
+        self.config.force_trace = Some(ForceTrace {
+            header,
+            authorize: Arc::new(authorize),
+        });
+        self
+    }
+
+    /// Record the request flavor, such as a canary or experiment tag, from the `header` request
+    /// header.
+    ///
+    /// The value is recorded as the `flavor` property on the request span, and
+    /// [`FastraceClientLayer`](crate::FastraceClientLayer) forwards the header on calls made
+    /// while handling the request, so canary comparisons can be done from trace data alone.
+    pub fn with_flavor_header(mut self, header: http::HeaderName) -> Self {
+        self.config.flavor_header = Some(header);
+        self
+    }
+
+    /// Forward the `tracestate` and `baggage` request headers on downstream calls.
+    ///
+    /// Only the span context survives a hop by default, dropping vendor trace state and baggage.
+    /// With this enabled, [`FastraceClientLayer`](crate::FastraceClientLayer) sends both headers,
+    /// as received, on calls made while handling the request, so the full W3C context survives
+    /// multi-hop chains. Baggage entries can be restricted per destination with
+    /// [`ForwardingRules`](crate::ForwardingRules).
+    pub fn with_context_forwarding(mut self, enabled: bool) -> Self {
+        self.config.context_forwarding = enabled;
+        self
+    }
+
+    /// Identify requests with the `header` request header, such as `x-request-id`.
+    ///
+    /// When the request carries no such header, a random id is generated and inserted into the
+    /// request headers, so handlers see it too. The id is recorded as the `request.id` property
+    /// on the request span, and [`FastraceClientLayer`](crate::FastraceClientLayer) forwards
+    /// the header on calls made while handling the request, bridging traces with logs searched
+    /// by request id.
+    pub fn with_request_id(mut self, header: http::HeaderName) -> Self {
+        self.config.request_id_header = Some(header);
+        self
+    }
+
+    /// Do not record requests whose `header` request header is set to `1` or `true`.
+    ///
+    /// Such requests are handled as if they were not sampled: their context is still propagated
+    /// to downstream calls, but no span is recorded. This keeps traffic like load tests out of
+    /// the trace storage. A forced trace takes precedence.
+    ///
+    /// ```
+    /// use fastrace_tonic::DISABLE_TRACE_HEADER;
+    /// use http::HeaderName;
+    ///
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_disable_header(HeaderName::from_static(DISABLE_TRACE_HEADER))
+    ///     .build();
+    /// ```
+    pub fn with_disable_header(mut self, header: http::HeaderName) -> Self {
+        self.config.disable_header = Some(header);
+        self
+    }
+
+    /// Configure a callback invoked with the `traceparent` header of requests where it is present
+    /// but cannot be decoded.
+    ///
+    /// The request is then handled as if the header was missing, and an `invalid traceparent`
+    /// event carrying the header value is added to its span. This only applies to the default
+    /// extraction.
+    pub fn on_invalid_context<F>(mut self, f: F) -> Self
+    where F: Fn(&http::HeaderValue) + Send + Sync + 'static {
+        self.config.invalid_context_handler = Some(Arc::new(f));
+        self
+    }
+
+    /// Record the `user-agent` and `grpc-accept-encoding` request headers as the
+    /// `user_agent.original` and `grpc.request.accept_encoding` properties.
+    ///
+    /// gRPC clients put their library and version in the user agent, such as
+    /// `grpc-go/1.64.0`, which helps telling which clients produce slow calls.
+    pub fn with_user_agent(mut self, enabled: bool) -> Self {
+        self.config.user_agent = enabled;
+        self
+    }
+
+    /// Record the compression settings of the request and the response.
+    ///
+    /// The `grpc-encoding` and `grpc-accept-encoding` headers are recorded as the
+    /// `grpc.request.encoding` and `grpc.request.accept_encoding` properties for the request,
+    /// and `grpc.response.encoding` and `grpc.response.accept_encoding` for the response.
+    pub fn with_compression(mut self, enabled: bool) -> Self {
+        self.config.compression = enabled;
+        self
+    }
+
+    /// Record the `content-length` of the request and the response, when present, as the
+    /// `http.request.body.size` and `http.response.body.size` properties.
+    ///
+    /// gRPC messages are usually streamed without a `content-length`, so this is mostly
+    /// useful for gRPC-Web and plain HTTP routes served alongside.
+    pub fn with_content_length(mut self, enabled: bool) -> Self {
+        self.config.content_length = enabled;
+        self
+    }
+
+    /// Record the listed request headers as `http.request.header.<name>` properties.
+    ///
+    /// Header names are matched case-insensitively, and repeated headers are recorded as a
+    /// comma-separated list. Headers absent from the request are not recorded.
+    ///
+    /// ```
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_captured_request_headers(["x-api-version", "x-client-region"])
+    ///     .build();
+    /// ```
+    pub fn with_captured_request_headers<I>(mut self, headers: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        self.config
+            .captured_request_headers
+            .extend(captured_headers(headers, "http.request.header"));
+        self
+    }
+
+    /// Record the listed response headers as `http.response.header.<name>` properties.
+    ///
+    /// Like [`ServerTracing::with_captured_request_headers`], for the headers of the response
+    /// head. Headers sent in trailers are not recorded.
+    ///
+    /// ```
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_captured_response_headers(["x-cache-status", "grpc-message"])
+    ///     .build();
+    /// ```
+    pub fn with_captured_response_headers<I>(mut self, headers: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: AsRef<str>,
+    {
+        self.config
+            .captured_response_headers
+            .extend(captured_headers(headers, "http.response.header"));
+        self
+    }
+
+    /// Redact captured header values before they are recorded.
+    ///
+    /// `f` is called with the property key and the value of every header captured by
+    /// [`ServerTracing::with_captured_request_headers`],
+    /// [`ServerTracing::with_captured_response_headers`], [`ServerTracing::with_user_agent`],
+    /// [`ServerTracing::with_compression`], [`ServerTracing::with_forwarded_for`] and
+    /// [`RouteConfig::with_captured_header`]. It returns the value to record, which may be
+    /// masked, or `None` to drop the property.
+    ///
+    /// ```
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_captured_request_headers(["x-api-version", "x-customer-email"])
+    ///     .with_redaction(|key, value| match key {
+    ///         "http.request.header.x-customer-email" => Some("<redacted>".to_owned()),
+    ///         _ => Some(value.to_owned()),
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn with_redaction<F>(mut self, f: F) -> Self
+    where F: Fn(&str, &str) -> Option<String> + Send + Sync + 'static {
+        self.config.redactor = Some(Arc::new(f));
+        self
+    }
+
+    /// Decorate request spans with the OpenTelemetry RPC semantic-convention attributes.
+    ///
+    /// The `rpc.system` (`grpc`), `rpc.service`, `rpc.method` and `server.address` properties
+    /// are recorded from the request. `rpc.grpc.status_code` is always recorded, and
+    /// `network.peer.address` is recorded when the peer address is configured with
+    /// [`ServerTracing::with_peer_address`] or given by an [`InProcessPeer`].
+    /// Backends such as the ones fed by `fastrace-opentelemetry` render these specially.
+    pub fn with_otel_semconv(mut self, enabled: bool) -> Self {
+        self.config.otel_semconv = enabled;
+        self
+    }
+
+    /// Delegate the creation of request spans to `f`.
+    ///
+    /// `f` receives the span name and the parent context resulting from extraction and
+    /// sampling, and the request head. It can return `Span::root(name, parent)`, which is what
+    /// the layer does by default, a child of a local parent, a noop span, or a span with
+    /// creation-time properties. The layer's own properties and events are added to the
+    /// returned span. This runs only for requests that are traced.
+    ///
+    /// ```
+    /// use fastrace::prelude::*;
+    ///
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_span_factory(|name, parent, parts| {
+    ///         Span::root(name, parent).with_property(|| ("http.method", parts.method.to_string()))
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn with_span_factory<F>(mut self, f: F) -> Self
+    where F: Fn(Cow<'static, str>, SpanContext, &http::request::Parts) -> Span
+            + Send
+            + Sync
+            + 'static {
+        self.config.span_factory = Some(Arc::new(f));
+        self
+    }
+
+    /// Record the parent contexts listed in the `header` request header of fan-in requests.
+    ///
+    /// Batch consumers forwarding the work of several upstream requests can list their contexts
+    /// in this header, as comma-separated `traceparent` values. When the request carries no
+    /// other parent, the first listed context is used as the parent. The other contexts are
+    /// recorded as `link.{index}` properties holding their `traceparent` encoding, so fan-in
+    /// relationships are not dropped.
+    ///
+    /// ```
+    /// use http::HeaderName;
+    ///
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_fan_in_header(HeaderName::from_static("x-fan-in-parents"))
+    ///     .build();
+    /// ```
+    pub fn with_fan_in_header(mut self, header: http::HeaderName) -> Self {
+        self.config.fan_in_header = Some(header);
+        self
+    }
+
+    /// Compute sampling decisions without enforcing them.
+    ///
+    /// In dry-run mode, every request is recorded, and the decision that would have been made
+    /// by the configured sampling options and the upstream service is recorded as the
+    /// `sampling.dry_run` property (`record` or `drop`). This allows evaluating a sampling
+    /// configuration against real traffic before enforcing it.
+    pub fn with_sampling_dry_run(mut self, enabled: bool) -> Self {
+        self.config.sampling_dry_run = enabled;
+        self
+    }
+
+    /// Embed the trace id of recorded requests in their gRPC error responses.
+    ///
+    /// See [`TraceIdInErrors`] for the available locations.
+    pub fn with_trace_id_in_errors(mut self, mode: TraceIdInErrors) -> Self {
+        self.config.trace_id_in_errors = Some(mode);
+        self
+    }
+
+    /// Record the properties returned by `f` on the request span.
+    ///
+    /// `f` is called once per recorded request, so it can derive properties from the request
+    /// without a dedicated layer.
+    ///
+    /// ```
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_properties(|parts| {
+    ///         let streaming = parts.headers.contains_key("x-streaming");
+    ///         [(
+    ///             "request.streaming",
+    ///             if streaming { "true" } else { "false" },
+    ///         )]
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn with_properties<F, I, K, V>(mut self, f: F) -> Self
+    where
+        F: Fn(&http::request::Parts) -> I + Send + Sync + 'static,
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<Cow<'static, str>>,
+        V: Into<Cow<'static, str>>,
+    {
+        self.config.properties = Some(Arc::new(move |parts| {
+            f(parts)
+                .into_iter()
+                .map(|(key, value)| (key.into(), value.into()))
+                .collect()
+        }));
+        self
+    }
+
+    /// Record the tenant of the request, as returned by `f`, as the `tenant.id` property.
+    ///
+    /// ```
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_tenant(|parts| {
+    ///         let tenant = parts.headers.get("x-tenant-id")?;
+    ///         tenant.to_str().ok().map(str::to_owned)
+    ///     })
+    ///     .build();
+    /// ```
+    pub fn with_tenant<F>(mut self, f: F) -> Self
+    where F: Fn(&http::request::Parts) -> Option<String> + Send + Sync + 'static {
+        self.config.tenant = Some(Arc::new(f));
+        self
+    }
+
+    /// Record the hops of the `forwarded` or `x-forwarded-for` request header as the
+    /// `http.forwarded_for` property.
+    ///
+    /// Behind load balancers, the peer address is the one of the closest proxy, while the
+    /// forwarded-for chain holds the address of the actual client. `hops` selects whether the
+    /// whole chain or only its first or last hop is recorded.
+    pub fn with_forwarded_for(mut self, hops: ForwardedFor) -> Self {
+        self.config.forwarded_for = Some(hops);
+        self
+    }
+
+    /// Record the remote address of the connection as the `network.peer.address` and
+    /// `network.peer.port` properties, using `f` to read it from the request extensions.
+    ///
+    /// The address is only available when the server inserts connect info into the
+    /// extensions. Tonic's `Server::serve` does, but `serve_with_incoming` requires the
+    /// incoming stream's connections to implement `Connected`, and other servers may need
+    /// to be set up with something like `into_make_service_with_connect_info`. The server only
+    /// inserts connect info per connection, so it cannot be checked when the layer is built, and
+    /// request spans without an address are left untouched. Wrap the make service in a
+    /// [`FastraceMakeService`](crate::FastraceMakeService) recording with
+    /// `record_connect_info` to get a `peer address unavailable` event on the `connection` span
+    /// of connections whose connect info holds no address.
+    ///
+    /// With the `transport` feature and no extractor configured, the address is read from
+    /// tonic's `TcpConnectInfo`, or `TlsConnectInfo` with the `tls` feature, whenever present.
+    /// On Unix, the peer credentials of Unix domain socket connections are also read from
+    /// tonic's `UdsConnectInfo` and recorded as the `unix.peer.uid`, `unix.peer.gid` and
+    /// `unix.peer.pid` properties.
+    ///
+    /// ```
+    /// use std::net::SocketAddr;
+    ///
+    /// #[derive(Clone)]
+    /// struct ConnectInfo(SocketAddr);
+    ///
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_peer_address(|extensions| extensions.get::<ConnectInfo>().map(|info| info.0))
+    ///     .build();
+    /// ```
+    pub fn with_peer_address<F>(mut self, f: F) -> Self
+    where F: Fn(&http::Extensions) -> Option<std::net::SocketAddr> + Send + Sync + 'static {
+        self.config.peer_address = Some(PeerAddress::new(Arc::new(f)));
+        self
+    }
+
+    /// Name request spans after a template instead of the request URI.
+    ///
+    /// The template can contain the placeholders `{http.method}`, `{url.path}`,
+    /// `{server.address}`, `{rpc.service}` and `{rpc.method}`, filled from the request. The
+    /// server address is the host of the request URI or, like the `server.address` property,
+    /// of the `Host` header, without the port. The gRPC placeholders are empty for paths that are
+    /// not of the form `/pkg.Service/Method`. Other text, including unknown placeholders, is
+    /// kept verbatim. A route's span name takes precedence.
+    ///
+    /// ```
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_span_name_template("{http.method} {rpc.service}/{rpc.method}")
+    ///     .build();
+    /// ```
+    pub fn with_span_name_template(mut self, template: &str) -> Self {
+        self.config.span_name_template = Some(SpanNameTemplate::parse(template));
+        self
+    }
+
+    /// Name request spans after a normalized request path instead of the request URI.
+    ///
+    /// This is meant for plain HTTP services, whose URIs carry query strings and entity ids that
+    /// would otherwise make span names unbounded. The query string is dropped, and path segments
+    /// made of digits or holding a UUID are replaced with `{id}` and `{uuid}`, so
+    /// `/users/42/orders?page=2` is named `/users/{id}/orders`. Route span names and
+    /// [`ServerTracing::with_span_name_template`] take precedence.
+    pub fn with_uri_normalization(mut self, enabled: bool) -> Self {
+        self.config.normalize_uri = enabled;
+        self
+    }
+
+    /// Emit one summary span per gRPC method and `interval`, covering every request whether it
+    /// was recorded or not.
+    ///
+    /// Summary spans are named `summary {service}/{method}` and carry the `summary.count`,
+    /// `summary.errors`, `summary.p99_us` and `summary.interval_ms` properties. With aggressive
+    /// sampling, they give trace-only backends coarse visibility on all traffic. The summaries
+    /// of all methods are emitted by the first request completing after the interval elapsed,
+    /// and errors are requests failing or answered with a non-zero `grpc-status` in a
+    /// Trailers-Only response. Since the path of a request is chosen by the client, at most 256
+    /// methods are summarized separately per interval, and the requests of further methods are
+    /// summarized together as `summary other`.
+    ///
+    /// ```
+    /// use std::time::Duration;
+    ///
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_sample_ratio(0.001)
+    ///     .with_summary_spans(Duration::from_secs(60))
+    ///     .build();
+    /// ```
+    pub fn with_summary_spans(mut self, interval: std::time::Duration) -> Self {
+        self.config.summary = Some(Arc::new(Summary::new(interval)));
+        self
+    }
+
+    /// Truncate span names to at most `max_len` bytes.
+    ///
+    /// Span names default to the request URI, which is controlled by the client, so an abusive
+    /// or buggy client can otherwise bloat the trace storage with arbitrarily long names.
+    /// Truncation happens on a UTF-8 character boundary.
+    pub fn with_max_span_name_len(mut self, max_len: usize) -> Self {
+        self.config.max_span_name_len = Some(max_len);
+        self
+    }
+
+    /// Record the streaming of the response body as a `response body` child span.
+    ///
+    /// The request span always lasts until the response body is finished. This child span
+    /// starts once the response head is ready, separating the time spent by the handler from
+    /// the time spent streaming messages, and is the local parent while the body is polled.
+    pub fn with_response_body_span(mut self, enabled: bool) -> Self {
+        self.config.response_body_span = enabled;
+        self
+    }
+
+    /// Record an event for every message received and sent.
+    ///
+    /// Each data frame of the request and response bodies gets a `message received` or
+    /// `message sent` event with its `index` in the body and its `size` in bytes, which shows
+    /// where a bidirectional stream stalls. gRPC messages usually map to a data frame each,
+    /// but large messages may be split across several frames.
+    pub fn with_message_events(mut self, enabled: bool) -> Self {
+        self.config.message_events = enabled;
+        self
+    }
+
+    /// Record the number of messages received and sent as the
+    /// `rpc.grpc.request.message_count` and `rpc.grpc.response.message_count` properties.
+    ///
+    /// Messages are counted as the data frames of the request and response bodies, and the
+    /// counts are recorded once the span ends. This is much cheaper than
+    /// [`ServerTracing::with_message_events`].
+    pub fn with_message_counts(mut self, enabled: bool) -> Self {
+        self.config.message_counts = enabled;
+        self
+    }
+
+    /// Record the number of body bytes received and sent as the `rpc.grpc.request.bytes` and
+    /// `rpc.grpc.response.bytes` properties.
+    ///
+    /// The sizes are accumulated over the data frames of the request and response bodies,
+    /// including the gRPC message framing, and recorded once the span ends.
+    pub fn with_body_bytes(mut self, enabled: bool) -> Self {
+        self.config.body_bytes = enabled;
+        self
+    }
+
+    /// Record a `first byte` event when the first data frame of the response body is sent.
+    ///
+    /// The event has an `elapsed_us` property holding the time to first byte since the request
+    /// started, in microseconds, like [`RequestSpan::checkpoint`]. For streaming responses,
+    /// this is usually more telling than the duration of the span.
+    pub fn with_time_to_first_byte(mut self, enabled: bool) -> Self {
+        self.config.time_to_first_byte = enabled;
+        self
+    }
+
+    /// Record the time spent polling the handler future and the time spent waiting for it to be
+    /// woken as the `busy_us` and `idle_us` properties, in microseconds.
+    ///
+    /// A high busy time points at a CPU-bound handler, a high idle time at a handler waiting on
+    /// I/O, such as downstream calls. Both are measured until the response head is ready.
+    pub fn with_poll_timing(mut self, enabled: bool) -> Self {
+        self.config.poll_timing = enabled;
+        self
+    }
+
+    /// Record a `first poll` event when the handler future is first polled, and a
+    /// `response ready` event when it returns the response head.
+    ///
+    /// Both events have an `elapsed_us` property like [`RequestSpan::checkpoint`]. The
+    /// `first poll` event shows the scheduling delay between the layer being called and the
+    /// executor running the handler, which grows when the runtime is overloaded.
+    pub fn with_poll_events(mut self, enabled: bool) -> Self {
+        self.config.poll_events = enabled;
+        self
+    }
+
+    /// Record the time the request waited for the inner service to become ready as the
+    /// `backpressure_us` property, in microseconds.
+    ///
+    /// The wait starts when `poll_ready` first returns `Pending`, such as when a
+    /// `ConcurrencyLimit` or `Buffer` layer below this one is saturated, and ends when it
+    /// returns ready. The property is only recorded when the request had to wait, making
+    /// queueing before the handler runs visible in traces.
+    pub fn with_backpressure(mut self, enabled: bool) -> Self {
+        self.config.backpressure = enabled;
+        self
+    }
+
+    /// Report recorded requests still in flight after `threshold`.
+    ///
+    /// A request span is only submitted once the request completes, so a stuck request
+    /// remains invisible until then. With this option, a `slow request` span with a
+    /// `threshold_ms` property is submitted in the trace of every request exceeding
+    /// `threshold`, as a child of the request span, while the request is still running. The
+    /// deadlines are tracked by a background thread started on first use.
+    pub fn with_watchdog(mut self, threshold: Duration) -> Self {
+        self.config.watchdog = Some(threshold);
+        self
+    }
+
+    /// Decide which responses count as failures.
+    ///
+    /// `f` is called with the status of every response of a recorded request: with the
+    /// `grpc-status` of the head for Trailers-Only and non-200 responses, and of the trailers
+    /// otherwise. Failures get an `error` property set to `true` and an `error` event carrying
+    /// the status. This replaces the default, which counts any non-zero `grpc-status` as a
+    /// failure.
+    ///
+    /// ```
+    /// // NOT_FOUND is an expected outcome, but UNAVAILABLE and INTERNAL are not.
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_failure_classifier(|status| matches!(status.grpc_status(), Some(13 | 14)))
+    ///     .build();
+    /// ```
+    pub fn with_failure_classifier<F>(mut self, f: F) -> Self
+    where F: Fn(&StatusInfo) -> bool + Send + Sync + 'static {
+        self.config.failure_classifier = Some(Arc::new(f));
+        self
+    }
+
+    /// Decode the `google.rpc.Status` of `grpc-status-details-bin` into span events.
+    ///
+    /// `RetryInfo`, `BadRequest` field violations and `ErrorInfo` details are recorded as
+    /// `retry info`, `field violation` and `error info` events, and other details as a
+    /// `status detail` event holding their type URL. This requires the `prost-types` feature.
+    #[cfg(feature = "prost-types")]
+    pub fn with_status_details(mut self, enabled: bool) -> Self {
+        self.config.status_details = enabled;
+        self
+    }
+
+    /// Decide which responses count as failures with a tower-http response classifier, such as
+    /// `GrpcErrorsAsFailures`.
+    ///
+    /// This is [`ServerTracing::with_failure_classifier`] for teams already standardized on
+    /// tower-http classifiers. It requires the `tower-http` feature.
+    ///
+    /// ```ignore
+    /// use tower_http::classify::GrpcCode;
+    /// use tower_http::classify::GrpcErrorsAsFailures;
+    ///
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_response_classifier(GrpcErrorsAsFailures::new().with_success(GrpcCode::NotFound))
+    ///     .build();
+    /// ```
+    #[cfg(feature = "tower-http")]
+    pub fn with_response_classifier<C>(self, classifier: C) -> Self
+    where C: tower_http::classify::ClassifyResponse + Clone + Send + Sync + 'static {
+        self.with_failure_classifier(move |status| {
+            status::is_failure_with(classifier.clone(), status)
+        })
+    }
+
+    /// Mark high-priority requests so they are preferred by retention budgets.
+    ///
+    /// Requests whose `header` request header is set to `high` get a `sampling.priority`
+    /// property set to `high` and are exempt from [`ServerTracing::with_rate_limit`]. Along with
+    /// failed requests, which get an `error` property set to `true` (see
+    /// [`ServerTracing::with_failure_classifier`]),
+    /// [`PriorityRetentionReporter`](crate::PriorityRetentionReporter) keeps such traces first
+    /// when its budget is tight.
+    ///
+    /// ```
+    /// use http::HeaderName;
+    ///
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_rate_limit(100)
+    ///     .with_priority_header(HeaderName::from_static("x-priority"))
+    ///     .build();
+    /// ```
+    pub fn with_priority_header(mut self, header: http::HeaderName) -> Self {
+        self.config.priority_header = Some(header);
+        self
+    }
+
+    /// Record `TCP_INFO` metrics of the connection when the response is ready.
+    ///
+    /// `f` returns the file descriptor of the connection's socket from the request extensions,
+    /// typically inserted by the connect info of a custom incoming stream. The round-trip time,
+    /// its variance, the number of retransmitted and lost segments and the congestion window
+    /// are recorded as `tcp.*` properties. The descriptor is duplicated when the request
+    /// arrives, so the metrics are read from the same socket even if the connection is closed
+    /// and its descriptor reused by then. This requires the `socket-metrics` feature and is
+    /// only available on Linux.
+    ///
+    /// ```
+    /// use std::os::fd::RawFd;
+    ///
+    /// #[derive(Clone)]
+    /// struct ConnectionFd(RawFd);
+    ///
+    /// let layer = fastrace_tonic::ServerTracing::new()
+    ///     .with_socket_metrics(|extensions| extensions.get::<ConnectionFd>().map(|fd| fd.0))
+    ///     .build();
+    /// ```
+    #[cfg(all(feature = "socket-metrics", target_os = "linux"))]
+    pub fn with_socket_metrics<F>(mut self, f: F) -> Self
+    where F: Fn(&http::Extensions) -> Option<std::os::fd::RawFd> + Send + Sync + 'static {
+        self.config.socket_fd = Some(Arc::new(f));
+        self
+    }
+
+    /// Record the identity of the client certificate of mTLS connections.
+    ///
+    /// The leaf certificate found in tonic's `TlsConnectInfo` is parsed for every request, and
+    /// its subject common name and DNS and URI subject alternative names are recorded as the
+    /// `tls.client.subject_cn` and `tls.client.san` properties. A `spiffe://` URI name is also
+    /// recorded as the `spiffe.id` property. This requires the `mtls` feature and is off by
+    /// default, so enabling the feature alone costs nothing per request.
+    #[cfg(feature = "mtls")]
+    pub fn with_client_identity(mut self, enabled: bool) -> Self {
+        self.config.client_identity = enabled;
+        self
+    }
+
+    /// Builds the server layer.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the configuration is invalid. Use [`ServerTracing::try_build`] to handle the
+    /// error instead.
+    pub fn build(self) -> FastraceServerLayer {
+        match self.try_build() {
+            Ok(layer) => layer,
+            Err(err) => panic!("invalid fastrace server configuration: {err}"),
+        }
+    }
+
+    /// Builds the server layer, checking the configuration for invalid or conflicting options.
+    pub fn try_build(self) -> Result<FastraceServerLayer, ConfigError> {
+        self.config.validate()?;
+        Ok(FastraceServerLayer {
+            config: Arc::new(self.config),
+        })
+    }
+}
+
+/// An invalid [`ServerTracing`] configuration.
+#[non_exhaustive]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// A sample ratio is NaN.
+    InvalidSampleRatio,
+    /// The maximum span name length is zero.
+    EmptySpanName,
+    /// An option that only applies to the default extraction was combined with a custom span
+    /// context extractor. Holds the name of the option.
+    IgnoredByCustomExtractor(&'static str),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::InvalidSampleRatio => write!(f, "a sample ratio is NaN"),
+            Self::EmptySpanName => write!(f, "the maximum span name length is zero"),
+            Self::IgnoredByCustomExtractor(option) => write!(
+                f,
+                "`{option}` has no effect with a custom span context extractor"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Server layer for intercepting and processing trace context in incoming requests.
+///
+/// This layer extracts tracing context from incoming requests and creates a new span
+/// for each request. Add this to your tonic server to automatically handle trace context
+/// propagation. By default, the layer uses the `traceparent` header to extract a span
+/// context and falls back to a random context when the header is missing or invalid. The
+/// propagation formats can be changed with cargo features, see [`propagation`].
+/// If the configured extractor returns `None`, a noop span is used.
+///
+/// If an earlier middleware already decoded the parent and stored a [`SpanContext`] in the
+/// request extensions, it is used as the parent without running the extractor. The created
+/// span is made available to handlers as a [`RequestSpan`] in the request extensions, along with
+/// the raw propagation headers as [`PropagationHeaders`].
+///
+/// Use [`ServerTracing`] to configure the layer.
+#[derive(Clone, Default)]
+pub struct FastraceServerLayer {
+    config: Arc<ServerConfig>,
+}
+
+impl FastraceServerLayer {
+    /// Returns a builder for the layer, equivalent to [`ServerTracing::new`].
+    ///
+    /// ```
+    /// let layer = fastrace_tonic::FastraceServerLayer::builder()
+    ///     .with_sample_ratio(0.1)
+    ///     .build();
+    /// ```
+    pub fn builder() -> ServerTracing {
+        ServerTracing::new()
+    }
+
+    /// Configure a custom span context extractor.
+    ///
+    /// Return `None` to keep the span as noop. See also
+    /// [`ServerTracing::with_span_context_extractor`].
+    pub fn with_span_context_extractor<F>(mut self, f: F) -> Self
+    where F: Fn(&http::HeaderMap) -> Option<SpanContext> + Send + Sync + 'static {
+        Arc::make_mut(&mut self.config).span_context_extractor =
+            Some(Arc::new(move |parts| f(&parts.headers).into()));
+        self
+    }
+}
+
+impl<S> Layer<S> for FastraceServerLayer {
+    type Service = FastraceServerService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        FastraceServerService {
+            service,
+            config: self.config.clone(),
+            pending_since: None,
+            ready_wait: None,
+        }
+    }
+}
+
+/// Server-side service that handles trace context propagation.
+///
+/// This service extracts trace context from incoming requests and creates
+/// spans to track the request processing. It wraps the inner service and augments
+/// it with tracing capabilities.
+#[derive(Clone)]
+pub struct FastraceServerService<S> {
+    pub(crate) service: S,
+    pub(crate) config: Arc<ServerConfig>,
+    /// When `poll_ready` first returned `Pending`, if it has not returned ready since.
+    pending_since: Option<Instant>,
+    /// How long the last successful `poll_ready` waited, until taken by `call`.
+    ready_wait: Option<Duration>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for FastraceServerService<S>
+where
+    S: Service<Request<FastraceRequestBody<ReqBody>>, Response = http::Response<ResBody>>,
+    ResBody: http_body::Body,
+{
+    type Response = http::Response<FastraceServerBody<ResBody>>;
+    type Error = S::Error;
+    type Future = FastraceServerFuture<S::Future>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        if !self.config.backpressure {
+            return self.service.poll_ready(cx);
+        }
+        match self.service.poll_ready(cx) {
+            Poll::Pending => {
+                self.pending_since.get_or_insert_with(Instant::now);
+                Poll::Pending
+            }
+            Poll::Ready(result) => {
+                self.ready_wait = self.pending_since.take().map(|since| since.elapsed());
+                Poll::Ready(result)
+            }
+        }
+    }
+
+    fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+        let (mut parts, body) = req.into_parts();
+        let config = self.config.for_request(&parts).clone();
+        if config.is_excluded(&parts) {
+            self.ready_wait = None;
+            let request = Request::from_parts(parts, FastraceRequestBody::passthrough(body));
+            return FastraceServerFuture::passthrough(self.service.call(request), config);
+        }
+        config.ensure_request_id(&mut parts.headers);
+        let summary = config
+            .summary
+            .as_ref()
+            .and_then(|_| Summary::start(parts.uri.path()));
+        let span = RequestSpan::with_body_stats(
+            config.make_span(&parts),
+            config.message_counts,
+            config.body_bytes,
+        );
+        if let Some(wait) = self.ready_wait.take() {
+            span.add_property(|| ("backpressure_us", wait.as_micros().to_string()));
+        }
+        if let Some(threshold) = config.watchdog {
+            watchdog::watch(&span, threshold);
+        }
+        let carrier = config.make_carrier(&parts);
+        let socket = config
+            .socket_fd
+            .as_ref()
+            .and_then(|socket_fd| socket_fd(&parts.extensions))
+            .and_then(socket::Socket::dup);
+        parts.extensions.insert(span.clone());
+        if let Some(headers) = PropagationHeaders::from_headers(&parts.headers) {
+            parts.extensions.insert(headers);
+        }
+        let body = FastraceRequestBody::new(body, span.clone(), config.message_events);
+        let request = Request::from_parts(parts, body);
+        let inner = match panic::catch_unwind(AssertUnwindSafe(|| self.service.call(request))) {
+            Ok(inner) => inner,
+            Err(payload) => {
+                span.record_panic(&*payload);
+                panic::resume_unwind(payload);
+            }
+        };
+        FastraceServerFuture {
+            inner,
+            span: Some(span),
+            carrier,
+            socket,
+            summary,
+            poll_timing: config.poll_timing.then(PollTiming::new),
+            awaiting_first_poll: config.poll_events,
+            config,
+        }
+    }
+}
+
+#[cfg(feature = "tonic")]
+impl<S: tonic::server::NamedService> tonic::server::NamedService for FastraceServerService<S> {
+    const NAME: &'static str = S::NAME;
+}
+
+pin_project_lite::pin_project! {
+    /// Response future of [`FastraceServerService`].
+    ///
+    /// The inner future is polled with the request span as the local parent. Once the response
+    /// is ready, it is recorded on the span and the span is handed over to the
+    /// [`FastraceServerBody`], which submits it once the body is finished. If the future is
+    /// dropped before the response is ready, the span gets a `cancelled` property and event.
+    pub struct FastraceServerFuture<F> {
+        #[pin]
+        inner: F,
+        span: Option<RequestSpan>,
+        carrier: Option<Arc<Carrier>>,
+        socket: Option<socket::Socket>,
+        summary: Option<summary::Pending>,
+        poll_timing: Option<PollTiming>,
+        awaiting_first_poll: bool,
+        config: Arc<ServerConfig>,
+    }
+
+    impl<F> PinnedDrop for FastraceServerFuture<F> {
+        fn drop(this: Pin<&mut Self>) {
+            if let Some(span) = this.project().span.take() {
+                span.record_cancelled();
+            }
+        }
+    }
+}
+
+impl<F> FastraceServerFuture<F> {
+    /// Wraps the response future of a request that is not traced, passing it through untouched.
+    fn passthrough(inner: F, config: Arc<ServerConfig>) -> Self {
+        Self {
+            inner,
+            span: None,
+            carrier: None,
+            socket: None,
+            summary: None,
+            poll_timing: None,
+            awaiting_first_poll: false,
+            config,
+        }
+    }
+}
+
+impl<F, ResBody, E> Future for FastraceServerFuture<F>
+where
+    F: Future<Output = Result<http::Response<ResBody>, E>>,
+    ResBody: http_body::Body,
+{
+    type Output = Result<http::Response<FastraceServerBody<ResBody>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+
+        if this.span.is_none() {
+            let result = ready!(this.inner.poll(cx));
+            return Poll::Ready(result.map(|response| {
+                let status = response.status();
+                response
+                    .map(|body| FastraceServerBody::new(body, None, status, this.config.clone()))
+            }));
+        }
+
+        let _guard = this.span.as_ref().map(|span| span.set_local_parent());
+        let _carrier_guard = this.carrier.as_ref().map(|carrier| carrier.set_current());
+        if std::mem::take(this.awaiting_first_poll) {
+            if let Some(span) = this.span.as_ref() {
+                span.checkpoint("first poll");
+            }
+        }
+        let poll_start = this.poll_timing.is_some().then(Instant::now);
+        let poll = panic::catch_unwind(AssertUnwindSafe(|| this.inner.poll(cx)));
+        if let (Some(timing), Some(poll_start)) = (this.poll_timing.as_mut(), poll_start) {
+            timing.busy += poll_start.elapsed();
+        }
+        let mut result = match poll {
+            Ok(poll) => ready!(poll),
+            Err(payload) => {
+                // Taken, so the span is not also marked as cancelled while unwinding.
+                if let Some(span) = this.span.take() {
+                    span.record_panic(&*payload);
+                }
+                panic::resume_unwind(payload);
+            }
+        };
+
+        let span = this.span.take();
+        if let Some(span) = &span {
+            if this.config.poll_events {
+                span.checkpoint("response ready");
+            }
+            if let Ok(response) = &mut result {
+                this.config.record_response(span, response);
+            }
+            if let Some(socket) = this.socket.take() {
+                socket.record_tcp_info(span);
+            }
+            if let Some(timing) = this.poll_timing.take() {
+                timing.record(span);
+            }
+            if result.is_err() {
+                span.add_property(|| ("error", "true"));
+                span.add_event(Event::new("error").with_property(|| ("error.type", "service")));
+            }
+        }
+
+        if let (Some(pending), Some(summary)) = (this.summary.take(), &this.config.summary) {
+            let error = match &result {
+                Ok(response) => {
+                    status::grpc_status(response.headers()).is_some_and(|code| code != 0)
+                }
+                Err(_) => true,
+            };
+            summary.finish(pending, error);
+        }
+
+        let config = this.config.clone();
+        Poll::Ready(result.map(|response| {
+            let status = response.status();
+            response.map(|body| FastraceServerBody::new(body, span, status, config))
+        }))
+    }
+}
+
+/// The time spent polling a future, and since it was created.
+struct PollTiming {
+    created: Instant,
+    busy: Duration,
+}
+
+impl PollTiming {
+    fn new() -> Self {
+        Self {
+            created: Instant::now(),
+            busy: Duration::ZERO,
+        }
+    }
+
+    fn record(self, span: &Span) {
+        let idle = self.created.elapsed().saturating_sub(self.busy);
+        span.add_properties(|| {
+            [
+                ("busy_us", self.busy.as_micros().to_string()),
+                ("idle_us", idle.as_micros().to_string()),
+            ]
+        });
+    }
+}
+
+impl ServerConfig {
+    /// Returns the configuration applying to a request.
+    pub(crate) fn for_request<'a>(
+        self: &'a Arc<Self>,
+        parts: &http::request::Parts,
+    ) -> &'a Arc<Self> {
+        if let Some(config) =
+            name::grpc_method(parts.uri.path()).and_then(|(service, _)| self.services.get(service))
+        {
+            return config;
+        }
+        if let Some(content_type) = content_type(&parts.headers) {
+            if let Some((_, config)) = self
+                .content_types
+                .iter()
+                .find(|(prefix, _)| content_type.starts_with(prefix.as_str()))
+            {
+                return config;
+            }
+        }
+        self
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        for config in self
+            .services
+            .values()
+            .chain(self.content_types.iter().map(|(_, config)| config))
+        {
+            config.validate()?;
+        }
+
+        let ratios = self
+            .sample_ratio
+            .iter()
+            .chain(self.method_sample_ratios.values())
+            .chain(
+                self.routes
+                    .iter()
+                    .filter_map(|(_, route)| route.sample_ratio.as_ref()),
+            );
+        for ratio in ratios {
+            if ratio.is_nan() {
+                return Err(ConfigError::InvalidSampleRatio);
+            }
+        }
+
+        if self.max_span_name_len == Some(0) {
+            return Err(ConfigError::EmptySpanName);
+        }
+
+        if self.span_context_extractor.is_some() {
+            if self.invalid_context_handler.is_some() {
+                return Err(ConfigError::IgnoredByCustomExtractor("on_invalid_context"));
+            }
+            if self.missing_context_policy != MissingContextPolicy::default() {
+                return Err(ConfigError::IgnoredByCustomExtractor(
+                    "with_missing_context_policy",
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns whether a request is passed through untouched, because tracing is toggled off or
+    /// the request is rejected by the gRPC-only check or the filter.
+    ///
+    /// This is checked before anything else, so such requests cost no more than the checks.
+    pub(crate) fn is_excluded(&self, parts: &http::request::Parts) -> bool {
+        if self
+            .toggle
+            .as_ref()
+            .is_some_and(|toggle| !toggle.is_enabled())
+        {
+            return true;
+        }
+
+        if self.grpc_only
+            && !content_type(&parts.headers)
+                .is_some_and(|content_type| content_type.starts_with("application/grpc"))
+        {
+            return true;
+        }
+
+        match &self.filter {
+            Some(filter) => !filter(parts),
+            None => false,
+        }
+    }
+
+    /// Creates the span for an incoming request that is not excluded.
+    ///
+    /// Checks are ordered from cheapest to most expensive: every step that can decide on a
+    /// noop span returns early, so header parsing and span name formatting only happen for
+    /// requests that are actually going to be traced.
+    pub(crate) fn make_span(&self, parts: &http::request::Parts) -> Span {
+        let route = route::find_route(&self.routes, parts.uri.path());
+        if route.is_some_and(|route| route.skip) {
+            return Span::noop();
+        }
+
+        let forced = self.force_trace.as_ref().is_some_and(|force_trace| {
+            parts.headers.contains_key(&force_trace.header) && (force_trace.authorize)(parts)
+        });
+
+        let high_priority = self.is_high_priority(&parts.headers);
+        let fan_in = self.fan_in_contexts(&parts.headers);
+        let extracted = self.extract(parts, route, fan_in.first());
+        let mut dry_run_decision = None;
+        let parent = if forced {
+            let mut parent = extracted.parent.unwrap_or_else(SpanContext::random);
+            parent.sampled = true;
+            parent
+        } else {
+            let Some(mut parent) = extracted.parent else {
+                return Span::noop();
+            };
+            if parent.sampled && self.is_disabled(&parts.headers) {
+                parent.sampled = false;
+            }
+            if parent.sampled && !high_priority {
+                if let Some(rate_limiter) = &self.rate_limiter {
+                    parent.sampled = rate_limiter.try_acquire();
+                }
+            }
+            if self.sampling_dry_run {
+                dry_run_decision = Some(parent.sampled);
+                parent.sampled = true;
+            }
+            parent
+        };
+
+        let name = match route.and_then(|route| route.span_name.clone()) {
+            Some(name) => name,
+            None => match &self.span_name_template {
+                Some(template) => template.render(parts),
+                None => match name::matched_path(parts) {
+                    Some(name) => name,
+                    None if self.normalize_uri => name::normalize_path(parts.uri.path()).into(),
+                    None => parts.uri.to_string().into(),
+                },
+            },
+        };
+        let name = match self.max_span_name_len {
+            Some(max_len) => name::truncate(name, max_len),
+            None => name,
+        };
+        let span = match &self.span_factory {
+            Some(factory) => factory(name, parent, parts),
+            None => Span::root(name, parent),
+        };
+        span.add_properties(|| {
+            [
+                ("http.request.method", parts.method.to_string()),
+                ("url.path", parts.uri.path().to_owned()),
+                (
+                    "network.protocol.version",
+                    protocol_version(parts.version).to_owned(),
+                ),
+            ]
+        });
+        if let Some(route) = route {
+            route.record(&span, &parts.headers, self.redactor.as_ref());
+        }
+        if forced {
+            span.add_property(|| ("force_trace", "true"));
+        }
+        if high_priority {
+            span.add_property(|| ("sampling.priority", "high"));
+        }
+        if let Some(sampled) = dry_run_decision {
+            span.add_property(|| ("sampling.dry_run", if sampled { "record" } else { "drop" }));
+        }
+        for (index, link) in fan_in
+            .iter()
+            .filter(|link| link.span_id != parent.span_id)
+            .enumerate()
+        {
+            span.add_property(|| (format!("link.{index}"), link.encode_w3c_traceparent()));
+        }
+        if let Some(traceparent) = extracted.invalid_traceparent {
+            span.add_event(Event::new("invalid traceparent").with_property(|| {
+                (
+                    "traceparent",
+                    String::from_utf8_lossy(traceparent.as_bytes()).into_owned(),
+                )
+            }));
+        }
+        if let Some(flavor) = self
+            .flavor_header
+            .as_ref()
+            .and_then(|header| parts.headers.get(header))
+            .and_then(|flavor| flavor.to_str().ok())
+        {
+            span.add_property(|| ("flavor", flavor.to_owned()));
+        }
+        if self.user_agent {
+            record_headers(&span, self.redactor.as_ref(), &parts.headers, &[(
+                "user-agent",
+                "user_agent.original",
+            )]);
+        }
+        if self.compression {
+            record_headers(&span, self.redactor.as_ref(), &parts.headers, &[(
+                "grpc-encoding",
+                "grpc.request.encoding",
+            )]);
+        }
+        if self.user_agent || self.compression {
+            record_headers(&span, self.redactor.as_ref(), &parts.headers, &[(
+                "grpc-accept-encoding",
+                "grpc.request.accept_encoding",
+            )]);
+        }
+        if self.content_length {
+            record_content_length(&span, &parts.headers, "http.request.body.size");
+        }
+        timeout::record_grpc_timeout(&span, &parts.headers);
+        if let Some(id) = self
+            .request_id_header
+            .as_ref()
+            .and_then(|header| parts.headers.get(header))
+            .and_then(|id| id.to_str().ok())
+        {
+            span.add_property(|| ("request.id", id.to_owned()));
+        }
+        for (header, property) in &self.captured_request_headers {
+            if let Some(value) = joined_header(&parts.headers, header) {
+                capture::add_property(&span, self.redactor.as_ref(), property.clone(), &value);
+            }
+        }
+        if let Some(properties) = &self.properties {
+            span.add_properties(|| properties(parts));
+        }
+        if let Some(tenant) = self.tenant.as_ref().and_then(|tenant| tenant(parts)) {
+            span.add_property(|| ("tenant.id", tenant));
+        }
+        if let Some(hops) = self
+            .forwarded_for
+            .and_then(|hops| hops.select(&parts.headers))
+        {
+            capture::add_property(&span, self.redactor.as_ref(), "http.forwarded_for", &hops);
+        }
+        if self.otel_semconv {
+            record_otel_semconv(&span, parts);
+        }
+        if let Some(peer) = parts.extensions.get::<InProcessPeer>() {
+            peer.record(&span);
+        } else if let Some(peer_address) = &self.peer_address {
+            peer_address.record(&span, &parts.extensions);
+        } else {
+            #[cfg(feature = "transport")]
+            if let Some(addr) = crate::peer::connect_info_remote_addr(&parts.extensions) {
+                crate::peer::record_socket_addr(&span, addr);
+            }
+        }
+        #[cfg(feature = "transport")]
+        crate::peer::record_tcp_transport(&span, &parts.extensions);
+        #[cfg(all(feature = "transport", unix))]
+        crate::peer::record_uds_peer(&span, &parts.extensions);
+        #[cfg(feature = "mtls")]
+        if self.client_identity {
+            crate::peer::record_peer_identity(&span, &parts.extensions);
+        }
+        span
+    }
+
+    /// Records the response head on the span before it is submitted.
+    fn record_response<B>(&self, span: &Span, response: &mut http::Response<B>) {
+        if let Some(status) = response.extensions().get::<CacheStatus>() {
+            span.add_property(|| ("cache.status", status.as_str()));
+        }
+
+        if self.compression {
+            record_headers(span, self.redactor.as_ref(), response.headers(), &[
+                ("grpc-encoding", "grpc.response.encoding"),
+                ("grpc-accept-encoding", "grpc.response.accept_encoding"),
+            ]);
+        }
+
+        if self.content_length {
+            record_content_length(span, response.headers(), "http.response.body.size");
+        }
+
+        for (header, property) in &self.captured_response_headers {
+            if let Some(value) = joined_header(response.headers(), header) {
+                capture::add_property(span, self.redactor.as_ref(), property.clone(), &value);
+            }
+        }
+
+        span.add_property(|| {
+            (
+                "http.response.status_code",
+                response.status().as_str().to_owned(),
+            )
+        });
+        let status = StatusInfo::new(response.status(), response.headers());
+        if status.grpc_status().is_some() || status.http_status() != http::StatusCode::OK {
+            self.record_status(span, status);
+        }
+
+        if let Some(mode) = self.trace_id_in_errors {
+            if let Some(context) = SpanContext::from_span(span).filter(|context| context.sampled) {
+                status::embed_trace_id(response.headers_mut(), context.trace_id, mode);
+            }
+        }
+    }
+
+    /// Records the final status of a response, found in the head of Trailers-Only and non-200
+    /// responses and in the trailers otherwise.
+    pub(crate) fn record_status(&self, span: &Span, status: StatusInfo) {
+        if let Some(code) = status.grpc_status() {
+            span.add_property(|| ("rpc.grpc.status_code", code.to_string()));
+        }
+        if let Some(message) = status.grpc_message() {
+            span.add_property(|| ("grpc.message", message.to_owned()));
+        }
+
+        #[cfg(feature = "prost-types")]
+        if self.status_details {
+            if let Some(details) = status.details() {
+                crate::details::record_details(span, &details);
+            }
+        }
+
+        let failed = match &self.failure_classifier {
+            Some(classifier) => classifier(&status),
+            None => status.grpc_status().is_some_and(|code| code != 0),
+        };
+        if failed {
+            span.add_property(|| ("error", "true"));
+            span.add_event(Event::new("error").with_properties(|| {
+                let grpc_status = status.grpc_status().map(|code| code.to_string());
+                let grpc_message = status.grpc_message().map(str::to_owned);
+                [(
+                    "http.response.status_code",
+                    status.http_status().as_str().to_owned(),
+                )]
+                .into_iter()
+                .chain(grpc_status.map(|code| ("rpc.grpc.status_code", code)))
+                .chain(grpc_message.map(|message| ("grpc.message", message)))
+            }));
+        }
+    }
+
+    fn is_disabled(&self, headers: &http::HeaderMap) -> bool {
+        self.disable_header
+            .as_ref()
+            .and_then(|header| headers.get(header))
+            .is_some_and(|value| value == "1" || value == "true")
+    }
+
+    fn is_high_priority(&self, headers: &http::HeaderMap) -> bool {
+        self.priority_header
+            .as_ref()
+            .and_then(|header| headers.get(header))
+            .is_some_and(|value| value == "high")
+    }
+
+    /// Inserts a generated request id into `headers` when request ids are enabled and the
+    /// request has none, returning the header and the generated id.
+    pub(crate) fn ensure_request_id(
+        &self,
+        headers: &mut http::HeaderMap,
+    ) -> Option<(&http::HeaderName, http::HeaderValue)> {
+        let header = self.request_id_header.as_ref()?;
+        if headers.contains_key(header) {
+            return None;
+        }
+        let id = http::HeaderValue::try_from(format!("{:032x}", TraceId::random().0)).ok()?;
+        headers.insert(header.clone(), id.clone());
+        Some((header, id))
+    }
+
+    /// Collects the request headers to forward on downstream calls.
+    pub(crate) fn make_carrier(&self, parts: &http::request::Parts) -> Option<Arc<Carrier>> {
+        let mut carrier = Carrier::default();
+        for header in [&self.flavor_header, &self.request_id_header]
+            .into_iter()
+            .flatten()
+        {
+            if let Some(value) = parts.headers.get(header) {
+                carrier.push(header.clone(), value.clone());
+            }
+        }
+        if self.context_forwarding {
+            for header in [TRACESTATE_HEADER, BAGGAGE_HEADER] {
+                if let Some(value) = parts.headers.get(header) {
+                    carrier.push(http::HeaderName::from_static(header), value.clone());
+                }
+            }
+        }
+        (!carrier.is_empty()).then(|| Arc::new(carrier))
+    }
+
+    fn extract<'a>(
+        &self,
+        parts: &'a http::request::Parts,
+        route: Option<&RouteConfig>,
+        fan_in_parent: Option<&SpanContext>,
+    ) -> Extracted<'a> {
+        if let Some(parent) = parts.extensions.get::<SpanContext>() {
+            return Extracted {
+                parent: Some(*parent),
+                invalid_traceparent: None,
+            };
+        }
+
+        if let Some(extractor) = &self.span_context_extractor {
+            let parent = match extractor(parts) {
+                ExtractionDecision::Trace(parent) => Some(parent),
+                ExtractionDecision::PropagateOnly(mut parent) => {
+                    parent.sampled = false;
+                    Some(parent)
+                }
+                ExtractionDecision::Ignore => None,
+            };
+            return Extracted {
+                parent,
+                invalid_traceparent: None,
+            };
+        }
+
+        if let Some(parent) = propagation::extract(&parts.headers).or(fan_in_parent.copied()) {
+            return Extracted {
+                parent: Some(parent),
+                invalid_traceparent: None,
+            };
+        }
+
+        let invalid_traceparent = parts.headers.get(TRACEPARENT_HEADER);
+        if let (Some(traceparent), Some(handler)) =
+            (invalid_traceparent, &self.invalid_context_handler)
+        {
+            handler(traceparent);
+        }
+
+        let parent = match self.missing_context_policy {
+            MissingContextPolicy::NewTrace => Some(self.new_trace_context(parts, route)),
+            MissingContextPolicy::Noop => None,
+            MissingContextPolicy::LocalParent => Some(
+                SpanContext::current_local_parent()
+                    .unwrap_or_else(|| self.new_trace_context(parts, route)),
+            ),
+        };
+        Extracted {
+            parent,
+            invalid_traceparent,
+        }
+    }
+
+    /// Decodes the contexts listed in the fan-in header, skipping invalid ones.
+    fn fan_in_contexts(&self, headers: &http::HeaderMap) -> Vec<SpanContext> {
+        let Some(header) = &self.fan_in_header else {
+            return Vec::new();
+        };
+        headers
+            .get_all(header)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .filter_map(|traceparent| SpanContext::decode_w3c_traceparent(traceparent.trim()))
+            .collect()
+    }
+
+    fn new_trace_context(
+        &self,
+        parts: &http::request::Parts,
+        route: Option<&RouteConfig>,
+    ) -> SpanContext {
+        let mut parent = SpanContext::random();
+        if let Some(ratio) = self.sample_ratio_for(parts.uri.path(), route) {
+            parent.sampled = sampling::sampled_by_ratio(parent.trace_id, ratio);
+        }
+        parent
+    }
+
+    fn sample_ratio_for(&self, path: &str, route: Option<&RouteConfig>) -> Option<f64> {
+        route
+            .and_then(|route| route.sample_ratio)
+            .or_else(|| {
+                let method = path.strip_prefix('/').unwrap_or(path);
+                self.method_sample_ratios.get(method).copied()
+            })
+            .or(self.sample_ratio)
+    }
+}
+
+/// Records the `(header, property)` pairs of `names` for the headers present in `headers`.
+fn record_headers(
+    span: &Span,
+    redactor: Option<&capture::Redactor>,
+    headers: &http::HeaderMap,
+    names: &[(&str, &'static str)],
+) {
+    for (header, property) in names {
+        if let Some(value) = headers.get(*header).and_then(|value| value.to_str().ok()) {
+            capture::add_property(span, redactor, *property, value);
+        }
+    }
+}
+
+/// Returns the lowercase header names and the `{prefix}.{name}` properties they are recorded as.
+fn captured_headers<I>(headers: I, prefix: &'static str) -> impl Iterator<Item = (String, String)>
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    headers.into_iter().map(move |header| {
+        let header = header.as_ref().to_ascii_lowercase();
+        let property = format!("{prefix}.{header}");
+        (header, property)
+    })
+}
+
+/// Returns the values of `name` joined with commas, skipping values that are not visible ASCII.
+fn joined_header(headers: &http::HeaderMap, name: &str) -> Option<String> {
+    let mut values = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok());
+    let first = values.next()?;
+    Some(values.fold(first.to_owned(), |mut joined, value| {
+        joined.push(',');
+        joined.push_str(value);
+        joined
+    }))
+}
+
+fn record_content_length(span: &Span, headers: &http::HeaderMap, property: &'static str) {
+    if let Some(len) = headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|len| len.to_str().ok())
+        .and_then(|len| len.parse::<u64>().ok())
+    {
+        span.add_property(|| (property, len.to_string()));
+    }
+}
+
+fn record_otel_semconv(span: &Span, parts: &http::request::Parts) {
+    span.add_property(|| ("rpc.system", "grpc"));
+    if let Some((service, method)) = name::grpc_method(parts.uri.path()) {
+        span.add_properties(|| {
+            [("rpc.service", service), ("rpc.method", method)]
+                .map(|(key, value)| (key, value.to_owned()))
+        });
+    }
+    if let Some(host) = name::server_address(parts) {
+        span.add_property(|| ("server.address", host.to_owned()));
+    }
+}
+
+fn protocol_version(version: http::Version) -> &'static str {
+    match version {
+        http::Version::HTTP_09 => "0.9",
+        http::Version::HTTP_10 => "1.0",
+        http::Version::HTTP_11 => "1.1",
+        http::Version::HTTP_2 => "2",
+        http::Version::HTTP_3 => "3",
+        _ => "unknown",
+    }
+}
+
+fn content_type(headers: &http::HeaderMap) -> Option<&str> {
+    headers
+        .get(http::header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+}
+
+/// The outcome of span context extraction.
+struct Extracted<'a> {
+    parent: Option<SpanContext>,
+    /// The `traceparent` header, if present but invalid.
+    invalid_traceparent: Option<&'a http::HeaderValue>,
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future::Ready;
+    use std::pin::pin;
+    use std::sync::Mutex;
+
+    use bytes::Bytes;
+    use fastrace::collector::SpanRecord;
+    use http_body::Frame;
+
+    use super::*;
+    use crate::testing;
+
+    /// Records the head of the last request.
+    #[derive(Clone, Default)]
+    struct Capture {
+        parts: Arc<Mutex<Option<http::request::Parts>>>,
+    }
+
+    impl<B> Service<Request<B>> for Capture {
+        type Response = http::Response<String>;
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<B>) -> Self::Future {
+            *self.parts.lock().unwrap() = Some(req.into_parts().0);
+            std::future::ready(Ok(http::Response::new(String::new())))
+        }
+    }
+
+    fn send(layer: &FastraceServerLayer) -> http::request::Parts {
+        let capture = Capture::default();
+        let request = Request::builder()
+            .uri("/helloworld.Greeter/SayHello")
+            .header(
+                TRACEPARENT_HEADER,
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .body(String::new())
+            .unwrap();
+        drop(layer.layer(capture.clone()).call(request));
+        let parts = capture.parts.lock().unwrap().take();
+        parts.unwrap()
+    }
+
+    #[test]
+    fn filtered_requests_are_passed_through() {
+        let layer = ServerTracing::new()
+            .with_request_id(http::HeaderName::from_static("x-request-id"))
+            .with_filter(|_| false)
+            .build();
+
+        let parts = send(&layer);
+        assert!(parts.extensions.get::<RequestSpan>().is_none());
+        assert!(parts.extensions.get::<PropagationHeaders>().is_none());
+        assert!(!parts.headers.contains_key("x-request-id"));
+    }
+
+    #[test]
+    fn accepted_requests_are_traced() {
+        let layer = ServerTracing::new()
+            .with_request_id(http::HeaderName::from_static("x-request-id"))
+            .with_filter(|_| true)
+            .build();
+
+        let parts = send(&layer);
+        assert!(parts.extensions.get::<RequestSpan>().is_some());
+        assert!(parts.extensions.get::<PropagationHeaders>().is_some());
+        assert!(parts.headers.contains_key("x-request-id"));
+    }
+
+    /// A tonic service reading the whole request body before responding.
+    #[cfg(feature = "transport")]
+    #[derive(Clone)]
+    struct Echo;
+
+    #[cfg(feature = "transport")]
+    impl tonic::server::NamedService for Echo {
+        const NAME: &'static str = "test.Echo";
+    }
+
+    #[cfg(feature = "transport")]
+    impl Service<Request<tonic::body::BoxBody>> for Echo {
+        type Response = http::Response<tonic::body::BoxBody>;
+        type Error = Infallible;
+        type Future = Pin<Box<dyn Future<Output = Result<Self::Response, Self::Error>> + Send>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<tonic::body::BoxBody>) -> Self::Future {
+            use http_body::Body;
+
+            let mut body = req.into_body();
+            Box::pin(async move {
+                while let Some(frame) =
+                    std::future::poll_fn(|cx| Pin::new(&mut body).poll_frame(cx)).await
+                {
+                    frame.unwrap();
+                }
+                Ok(http::Response::new(tonic::body::empty_body()))
+            })
+        }
+    }
+
+    #[cfg(feature = "transport")]
+    #[test]
+    fn layers_onto_a_tonic_router() {
+        use http_body::Body;
+
+        testing::init();
+        let mut service = tonic::transport::Server::builder()
+            .layer(FastraceServerLayer::default())
+            .add_service(Echo)
+            .into_service::<tonic::body::BoxBody>();
+
+        let request = Request::builder()
+            .uri("/test.Echo/Say")
+            .header("content-type", "application/grpc")
+            .header(
+                TRACEPARENT_HEADER,
+                "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01",
+            )
+            .body(tonic::body::boxed(String::from("hello")))
+            .unwrap();
+        testing::block_on(std::future::poll_fn(|cx| service.poll_ready(cx))).unwrap();
+        let response = testing::block_on(service.call(request)).unwrap();
+        assert!(response.body().is_end_stream());
+        drop(response);
+
+        let spans = testing::spans(TraceId(0x0af7651916cd43dd8448eb211c80319c));
+        let span = testing::span(&spans, "/test.Echo/Say");
+        assert!(testing::has_event(span, "request complete"));
+    }
+
+    /// A response body sending one message, followed by `trailers` if any.
+    struct TestBody {
+        data: Option<Bytes>,
+        trailers: Option<http::HeaderMap>,
+    }
+
+    impl TestBody {
+        fn new(trailers: Option<http::HeaderMap>) -> Self {
+            Self {
+                data: Some(Bytes::from_static(b"message")),
+                trailers,
+            }
+        }
+    }
+
+    impl http_body::Body for TestBody {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            if let Some(data) = self.data.take() {
+                return Poll::Ready(Some(Ok(Frame::data(data))));
+            }
+            Poll::Ready(
+                self.trailers
+                    .take()
+                    .map(|trailers| Ok(Frame::trailers(trailers))),
+            )
+        }
+    }
+
+    type HandlerResult = Result<http::Response<TestBody>, Infallible>;
+
+    type HandlerFuture = Pin<Box<dyn Future<Output = HandlerResult> + Send>>;
+
+    /// An inner service answering every request with the future returned by its function.
+    #[derive(Clone)]
+    struct Handler<F>(F);
+
+    impl<B, F> Service<Request<B>> for Handler<F>
+    where F: FnMut() -> HandlerFuture
+    {
+        type Response = http::Response<TestBody>;
+        type Error = Infallible;
+        type Future = HandlerFuture;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _: Request<B>) -> Self::Future {
+            (self.0)()
+        }
+    }
+
+    fn respond(response: http::Response<TestBody>) -> HandlerFuture {
+        Box::pin(std::future::ready(Ok(response)))
+    }
+
+    fn ok() -> HandlerFuture {
+        respond(http::Response::new(TestBody::new(None)))
+    }
+
+    /// Answers once `delay` has passed since it was first polled, waking itself meanwhile.
+    fn delayed(delay: Duration) -> HandlerFuture {
+        let mut deadline = None;
+        Box::pin(std::future::poll_fn(move |cx| {
+            let deadline = *deadline.get_or_insert_with(|| Instant::now() + delay);
+            if Instant::now() < deadline {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            Poll::Ready(Ok(http::Response::new(TestBody::new(None))))
+        }))
+    }
+
+    /// Returns a request for `path` continuing a new sampled trace.
+    fn traced_request(path: &str, content_type: &str) -> (TraceId, Request<String>) {
+        let parent = SpanContext::new(TraceId::random(), SpanId::random());
+        let request = Request::builder()
+            .uri(path)
+            .header("content-type", content_type)
+            .header(TRACEPARENT_HEADER, parent.encode_w3c_traceparent())
+            .body(String::new())
+            .unwrap();
+        (parent.trace_id, request)
+    }
+
+    /// Sends a gRPC request for `path` through `layer` to a handler answering with `handler`,
+    /// reads the whole response and returns the spans of its trace.
+    fn serve(
+        layer: &FastraceServerLayer,
+        path: &str,
+        handler: fn() -> HandlerFuture,
+    ) -> Vec<SpanRecord> {
+        testing::init();
+        let (trace_id, request) = traced_request(path, "application/grpc");
+        let future = layer.layer(Handler(handler)).call(request);
+        let response = testing::block_on(future).unwrap();
+        let mut body = pin!(response.into_body());
+        while testing::block_on(std::future::poll_fn(|cx| {
+            http_body::Body::poll_frame(body.as_mut(), cx)
+        }))
+        .is_some()
+        {}
+        drop(body);
+        testing::spans(trace_id)
+    }
+
+    #[test]
+    fn watchdog_reports_requests_in_flight() {
+        let layer = ServerTracing::new()
+            .with_watchdog(Duration::from_millis(1))
+            .build();
+
+        let spans = serve(&layer, "/test.Watchdog/Slow", || {
+            delayed(Duration::from_millis(200))
+        });
+        let request = testing::span(&spans, "/test.Watchdog/Slow");
+        let slow = testing::span(&spans, "slow request");
+        assert_eq!(slow.parent_id, request.span_id);
+        assert_eq!(testing::property(slow, "threshold_ms"), Some("1"));
+    }
+
+    #[test]
+    fn watchdog_ignores_fast_requests() {
+        let layer = ServerTracing::new()
+            .with_watchdog(Duration::from_secs(60))
+            .build();
+
+        let spans = serve(&layer, "/test.Watchdog/Fast", ok);
+        assert!(spans.iter().all(|span| span.name != "slow request"));
+    }
+
+    #[test]
+    fn poll_timing_is_recorded() {
+        let layer = ServerTracing::new().with_poll_timing(true).build();
+
+        let spans = serve(&layer, "/test.Poll/Timing", || {
+            delayed(Duration::from_millis(10))
+        });
+        let span = testing::span(&spans, "/test.Poll/Timing");
+        let busy_us: u64 = testing::property(span, "busy_us").unwrap().parse().unwrap();
+        let idle_us: u64 = testing::property(span, "idle_us").unwrap().parse().unwrap();
+        assert!(busy_us + idle_us >= 10_000);
+    }
+
+    #[test]
+    fn dropped_requests_are_cancelled() {
+        testing::init();
+        let layer = FastraceServerLayer::default();
+        let (trace_id, request) = traced_request("/test.Cancel/Drop", "application/grpc");
+        let mut service = layer.layer(Handler(|| -> HandlerFuture {
+            Box::pin(std::future::pending::<HandlerResult>())
+        }));
+
+        let mut future = Box::pin(service.call(request));
+        assert!(testing::poll_once(future.as_mut()).is_pending());
+        drop(future);
+
+        let spans = testing::spans(trace_id);
+        let span = testing::span(&spans, "/test.Cancel/Drop");
+        assert_eq!(testing::property(span, "cancelled"), Some("true"));
+        assert!(testing::has_event(span, "cancelled"));
+    }
+
+    #[test]
+    fn handler_panics_are_recorded() {
+        testing::init();
+        let layer = FastraceServerLayer::default();
+        let (trace_id, request) = traced_request("/test.Panic/Poll", "application/grpc");
+        let mut service = layer.layer(Handler(|| -> HandlerFuture {
+            Box::pin(std::future::poll_fn(|_| -> Poll<HandlerResult> {
+                panic!("handler failed")
+            }))
+        }));
+
+        let future = service.call(request);
+        let result = panic::catch_unwind(AssertUnwindSafe(|| testing::block_on(future)));
+        assert!(result.is_err());
+
+        let spans = testing::spans(trace_id);
+        let span = testing::span(&spans, "/test.Panic/Poll");
+        assert_eq!(testing::property(span, "error"), Some("true"));
+        assert_eq!(testing::property(span, "cancelled"), None);
+        let event = span
+            .events
+            .iter()
+            .find(|event| event.name == "panic")
+            .unwrap();
+        assert_eq!(event.properties, [(
+            Cow::Borrowed("message"),
+            Cow::Borrowed("handler failed")
+        )]);
+    }
+
+    #[test]
+    fn trailers_are_recorded() {
+        let layer = FastraceServerLayer::default();
+
+        let spans = serve(&layer, "/test.Trailers/Denied", || {
+            let mut trailers = http::HeaderMap::new();
+            trailers.insert("grpc-status", http::HeaderValue::from_static("7"));
+            trailers.insert("grpc-message", http::HeaderValue::from_static("denied"));
+            respond(http::Response::new(TestBody::new(Some(trailers))))
+        });
+        let span = testing::span(&spans, "/test.Trailers/Denied");
+        assert_eq!(testing::property(span, "rpc.grpc.status_code"), Some("7"));
+        assert_eq!(testing::property(span, "grpc.message"), Some("denied"));
+        assert_eq!(testing::property(span, "error"), Some("true"));
+        assert!(testing::has_event(span, "trailers sent"));
+        assert!(testing::has_event(span, "response complete"));
+    }
+
+    #[test]
+    fn summary_counts_trailers_only_errors() {
+        let layer = ServerTracing::new()
+            .with_summary_spans(Duration::ZERO)
+            .build();
+
+        serve(&layer, "/test.SummaryTrailersOnly/Call", || {
+            let mut response = http::Response::new(TestBody::new(None));
+            response
+                .headers_mut()
+                .insert("grpc-status", http::HeaderValue::from_static("5"));
+            respond(response)
+        });
+        serve(&layer, "/test.SummaryTrailers/Call", || {
+            let mut trailers = http::HeaderMap::new();
+            trailers.insert("grpc-status", http::HeaderValue::from_static("5"));
+            respond(http::Response::new(TestBody::new(Some(trailers))))
+        });
+
+        let summaries = testing::spans_named("summary test.SummaryTrailersOnly/Call");
+        assert_eq!(testing::property(&summaries[0], "summary.count"), Some("1"));
+        assert_eq!(
+            testing::property(&summaries[0], "summary.errors"),
+            Some("1")
+        );
+        // The status in the trailers is only known once the response body is finished.
+        let summaries = testing::spans_named("summary test.SummaryTrailers/Call");
+        assert_eq!(testing::property(&summaries[0], "summary.count"), Some("1"));
+        assert_eq!(
+            testing::property(&summaries[0], "summary.errors"),
+            Some("0")
+        );
+    }
+
+    #[test]
+    fn services_use_their_own_config() {
+        let layer = ServerTracing::new()
+            .with_filter(|_| false)
+            .with_service("test.Traced", ServerTracing::new())
+            .build();
+
+        let spans = serve(&layer, "/test.Traced/Call", ok);
+        testing::span(&spans, "/test.Traced/Call");
+        let spans = serve(&layer, "/test.Untraced/Call", ok);
+        assert!(spans.is_empty());
+    }
+
+    #[test]
+    fn content_types_use_their_own_config() {
+        testing::init();
+        let layer = ServerTracing::new()
+            .with_filter(|_| false)
+            .with_content_type("application/json", ServerTracing::new())
+            .build();
+
+        let (trace_id, request) = traced_request("/users", "application/json");
+        drop(testing::block_on(layer.layer(Handler(ok)).call(request)));
+        let spans = testing::spans(trace_id);
+        testing::span(&spans, "/users");
+
+        let spans = serve(&layer, "/test.Grpc/Call", ok);
+        assert!(spans.is_empty());
+    }
+}
@@ -0,0 +1,83 @@
+use fastrace::prelude::*;
+
+/// A connection's socket, as returned by the hook configured with
+/// [`ServerTracing::with_socket_metrics`](crate::ServerTracing::with_socket_metrics).
+#[cfg(all(feature = "socket-metrics", target_os = "linux"))]
+pub(crate) type SocketFd = std::os::fd::RawFd;
+
+/// Socket metrics are not supported on this platform, so no socket can be obtained.
+#[cfg(not(all(feature = "socket-metrics", target_os = "linux")))]
+pub(crate) enum SocketFd {}
+
+/// A duplicate of a connection's socket descriptor, closed when dropped.
+///
+/// Holding a duplicate keeps the socket open, so the descriptor cannot be reused by another
+/// connection before the metrics are read.
+#[cfg(all(feature = "socket-metrics", target_os = "linux"))]
+pub(crate) struct Socket(std::os::fd::OwnedFd);
+
+#[cfg(not(all(feature = "socket-metrics", target_os = "linux")))]
+pub(crate) enum Socket {}
+
+#[cfg(all(feature = "socket-metrics", target_os = "linux"))]
+impl Socket {
+    /// Duplicates `fd`, returning `None` when it is not an open descriptor.
+    pub(crate) fn dup(fd: SocketFd) -> Option<Self> {
+        use std::os::fd::FromRawFd;
+
+        // SAFETY: duplicating a descriptor does not affect the original. An invalid descriptor
+        // makes the call fail.
+        let dup = unsafe { libc::fcntl(fd, libc::F_DUPFD_CLOEXEC, 0) };
+        if dup < 0 {
+            return None;
+        }
+        // SAFETY: `dup` is a new descriptor owned by nothing else.
+        Some(Self(unsafe { std::os::fd::OwnedFd::from_raw_fd(dup) }))
+    }
+
+    /// Records `TCP_INFO` metrics of the socket on the span.
+    ///
+    /// Nothing is recorded when the socket is not a TCP socket.
+    pub(crate) fn record_tcp_info(self, span: &Span) {
+        use std::os::fd::AsRawFd;
+
+        // SAFETY: `tcp_info` is a plain C struct for which all zeroes is a valid value.
+        let mut info: libc::tcp_info = unsafe { std::mem::zeroed() };
+        let mut len = std::mem::size_of::<libc::tcp_info>() as libc::socklen_t;
+        // SAFETY: `info` and `len` describe a writable buffer of the right size, and the
+        // descriptor is open while `self` is alive.
+        let ret = unsafe {
+            libc::getsockopt(
+                self.0.as_raw_fd(),
+                libc::IPPROTO_TCP,
+                libc::TCP_INFO,
+                &mut info as *mut libc::tcp_info as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret != 0 {
+            return;
+        }
+
+        span.add_properties(|| {
+            [
+                ("tcp.rtt_us", info.tcpi_rtt.to_string()),
+                ("tcp.rtt_var_us", info.tcpi_rttvar.to_string()),
+                ("tcp.retransmits", info.tcpi_total_retrans.to_string()),
+                ("tcp.lost", info.tcpi_lost.to_string()),
+                ("tcp.snd_cwnd", info.tcpi_snd_cwnd.to_string()),
+            ]
+        });
+    }
+}
+
+#[cfg(not(all(feature = "socket-metrics", target_os = "linux")))]
+impl Socket {
+    pub(crate) fn dup(fd: SocketFd) -> Option<Self> {
+        match fd {}
+    }
+
+    pub(crate) fn record_tcp_info(self, _span: &Span) {
+        match self {}
+    }
+}
@@ -0,0 +1,229 @@
+use std::any::Any;
+use std::ops::Deref;
+use std::sync::Arc;
+use std::sync::Weak;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+use fastrace::prelude::*;
+
+use crate::inflight::InFlightGuard;
+
+/// A handle to the span created by [`FastraceServerLayer`](crate::FastraceServerLayer) for a
+/// request.
+///
+/// The layer inserts it into the request extensions, so handlers can enrich the request span
+/// with properties and events. It dereferences to [`Span`]. The span is submitted once the
+/// response body is finished and every handle has been dropped, so avoid keeping handles beyond
+/// the request.
+///
+/// ```
+/// use fastrace::prelude::*;
+/// use fastrace_tonic::RequestSpan;
+///
+/// fn handle(request: &http::Request<()>) {
+///     if let Some(span) = request.extensions().get::<RequestSpan>() {
+///         span.add_property(|| ("user.id", "42"));
+///         span.add_event(Event::new("user loaded"));
+///     }
+/// }
+/// ```
+#[derive(Clone)]
+pub struct RequestSpan {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    // Dropped first, so the span is submitted before it stops being in flight.
+    span: Span,
+    start: Instant,
+    body_stats: Option<BodyStats>,
+    _in_flight: InFlightGuard,
+}
+
+/// The number of data frames and bytes of the request and response bodies.
+#[derive(Default)]
+struct BodyStats {
+    record_messages: bool,
+    record_bytes: bool,
+    request_messages: AtomicU64,
+    request_bytes: AtomicU64,
+    response_messages: AtomicU64,
+    response_bytes: AtomicU64,
+}
+
+impl RequestSpan {
+    pub(crate) fn new(span: Span) -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                span,
+                start: Instant::now(),
+                body_stats: None,
+                _in_flight: InFlightGuard::new(),
+            }),
+        }
+    }
+
+    /// Like [`RequestSpan::new`], accumulating the data frames reported with
+    /// [`RequestSpan::count_request_frame`] and [`RequestSpan::count_response_frame`] and
+    /// recording the number of messages, the number of bytes, or both, once the span ends.
+    pub(crate) fn with_body_stats(span: Span, messages: bool, bytes: bool) -> Self {
+        if !messages && !bytes {
+            return Self::new(span);
+        }
+        Self {
+            inner: Arc::new(Inner {
+                span,
+                start: Instant::now(),
+                body_stats: Some(BodyStats {
+                    record_messages: messages,
+                    record_bytes: bytes,
+                    ..BodyStats::default()
+                }),
+                _in_flight: InFlightGuard::new(),
+            }),
+        }
+    }
+
+    /// Marks the request as cancelled, such as by a client disconnection or a timeout layer
+    /// dropping its future or response body before completion.
+    pub(crate) fn record_cancelled(&self) {
+        self.inner.span.add_property(|| ("cancelled", "true"));
+        self.checkpoint("cancelled");
+    }
+
+    /// Records a panic of the handler, with its message when it is a string.
+    pub(crate) fn record_panic(&self, payload: &(dyn Any + Send)) {
+        let message = payload
+            .downcast_ref::<&str>()
+            .copied()
+            .or_else(|| payload.downcast_ref::<String>().map(String::as_str))
+            .unwrap_or("Box<dyn Any>")
+            .to_owned();
+        self.inner.span.add_property(|| ("error", "true"));
+        self.inner
+            .span
+            .add_event(Event::new("panic").with_property(|| ("message", message)));
+    }
+
+    pub(crate) fn downgrade(&self) -> WeakRequestSpan {
+        WeakRequestSpan {
+            inner: Arc::downgrade(&self.inner),
+        }
+    }
+
+    pub(crate) fn count_request_frame(&self, size: usize) {
+        if let Some(stats) = &self.inner.body_stats {
+            stats.request_messages.fetch_add(1, Ordering::Relaxed);
+            stats
+                .request_bytes
+                .fetch_add(size as u64, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn count_response_frame(&self, size: usize) {
+        if let Some(stats) = &self.inner.body_stats {
+            stats.response_messages.fetch_add(1, Ordering::Relaxed);
+            stats
+                .response_bytes
+                .fetch_add(size as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Marks the end of a request phase, such as `parsed` or `authorized`.
+    ///
+    /// This adds an event named `name` with an `elapsed_us` property holding the time elapsed
+    /// since the request started, in microseconds. Checkpoints show phase timing in trace UIs at
+    /// the cost of an event rather than a nested span.
+    ///
+    /// ```
+    /// use fastrace_tonic::RequestSpan;
+    ///
+    /// fn handle(request: &http::Request<()>) {
+    ///     let span = request.extensions().get::<RequestSpan>().unwrap();
+    ///     // Parse the request.
+    ///     span.checkpoint("parsed");
+    /// }
+    /// ```
+    pub fn checkpoint(&self, name: &'static str) {
+        let elapsed = self.inner.start.elapsed();
+        self.inner.span.add_event(
+            Event::new(name).with_property(|| ("elapsed_us", elapsed.as_micros().to_string())),
+        );
+    }
+}
+
+/// A handle to a [`RequestSpan`] that does not keep it from being submitted.
+pub(crate) struct WeakRequestSpan {
+    inner: Weak<Inner>,
+}
+
+impl WeakRequestSpan {
+    /// Returns whether the span has not been submitted yet.
+    pub(crate) fn is_in_flight(&self) -> bool {
+        self.inner.strong_count() > 0
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        let Some(stats) = &self.body_stats else {
+            return;
+        };
+        if stats.record_messages {
+            let request = stats.request_messages.load(Ordering::Relaxed);
+            let response = stats.response_messages.load(Ordering::Relaxed);
+            self.span.add_properties(|| {
+                [
+                    ("rpc.grpc.request.message_count", request.to_string()),
+                    ("rpc.grpc.response.message_count", response.to_string()),
+                ]
+            });
+        }
+        if stats.record_bytes {
+            let request = stats.request_bytes.load(Ordering::Relaxed);
+            let response = stats.response_bytes.load(Ordering::Relaxed);
+            self.span.add_properties(|| {
+                [
+                    ("rpc.grpc.request.bytes", request.to_string()),
+                    ("rpc.grpc.response.bytes", response.to_string()),
+                ]
+            });
+        }
+    }
+}
+
+impl Deref for RequestSpan {
+    type Target = Span;
+
+    fn deref(&self) -> &Span {
+        &self.inner.span
+    }
+}
+
+/// Runs `f` with the [`RequestSpan`] found in `extensions` as the local parent.
+///
+/// The local parent is set per thread, so synchronous work offloaded with `spawn_blocking`,
+/// `block_in_place` or a rayon pool is not attached to the request span by default. Wrapping
+/// that work with this function makes the spans it creates children of the request span. When
+/// `extensions` holds no [`RequestSpan`], `f` runs unchanged.
+///
+/// ```
+/// use fastrace::prelude::*;
+///
+/// fn handle(request: &http::Request<()>) {
+///     let extensions = request.extensions().clone();
+///     std::thread::spawn(move || {
+///         fastrace_tonic::sync_scope(&extensions, || {
+///             let _span = LocalSpan::enter_with_local_parent("crunch numbers");
+///         })
+///     });
+/// }
+/// ```
+pub fn sync_scope<R>(extensions: &http::Extensions, f: impl FnOnce() -> R) -> R {
+    let _guard = extensions
+        .get::<RequestSpan>()
+        .map(|span| span.set_local_parent());
+    f()
+}
@@ -0,0 +1,298 @@
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD_NO_PAD;
+use fastrace::prelude::*;
+use http::HeaderMap;
+use http::HeaderValue;
+
+const GRPC_STATUS: &str = "grpc-status";
+const GRPC_MESSAGE: &str = "grpc-message";
+const GRPC_STATUS_DETAILS: &str = "grpc-status-details-bin";
+const REQUEST_INFO_TYPE_URL: &str = "type.googleapis.com/google.rpc.RequestInfo";
+
+/// Where [`FastraceServerLayer`](crate::FastraceServerLayer) embeds the trace id of a recorded
+/// request in its gRPC error response, so clients always get the id needed to report the error.
+///
+/// This applies to error responses without a body (Trailers-Only responses), which is how tonic
+/// responds when a handler returns an error.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TraceIdInErrors {
+    /// Append ` [trace_id=<trace id>]` to the `grpc-message`.
+    Message,
+    /// Add a `google.rpc.RequestInfo` entry whose `request_id` is the trace id to the details
+    /// of `grpc-status-details-bin`.
+    StatusDetails,
+}
+
+/// The status of a response, as passed to the classifier configured with
+/// [`ServerTracing::with_failure_classifier`](crate::ServerTracing::with_failure_classifier).
+///
+/// The gRPC status is read from the response head for Trailers-Only responses and from the
+/// trailers otherwise.
+#[derive(Clone, Copy, Debug)]
+pub struct StatusInfo<'a> {
+    http_status: http::StatusCode,
+    grpc_status: Option<u32>,
+    grpc_message: Option<&'a str>,
+    #[cfg(feature = "prost-types")]
+    details: Option<&'a HeaderValue>,
+}
+
+impl<'a> StatusInfo<'a> {
+    pub(crate) fn new(http_status: http::StatusCode, headers: &'a HeaderMap) -> Self {
+        Self {
+            http_status,
+            grpc_status: grpc_status(headers),
+            grpc_message: headers
+                .get(GRPC_MESSAGE)
+                .and_then(|message| message.to_str().ok()),
+            #[cfg(feature = "prost-types")]
+            details: headers.get(GRPC_STATUS_DETAILS),
+        }
+    }
+
+    /// Returns the HTTP status of the response.
+    pub fn http_status(&self) -> http::StatusCode {
+        self.http_status
+    }
+
+    /// Returns the gRPC status code, such as `5` for `NOT_FOUND`, if any.
+    pub fn grpc_status(&self) -> Option<u32> {
+        self.grpc_status
+    }
+
+    /// Returns the gRPC status message, as sent in the percent-encoded `grpc-message` header.
+    pub fn grpc_message(&self) -> Option<&'a str> {
+        self.grpc_message
+    }
+
+    /// Returns the encoded `google.rpc.Status` of `grpc-status-details-bin`, if any.
+    #[cfg(feature = "prost-types")]
+    pub(crate) fn details(&self) -> Option<Vec<u8>> {
+        decode_details(self.details?)
+    }
+}
+
+/// Classifies a response status with a tower-http classifier.
+///
+/// The classifier sees a response head carrying the status, and the `grpc-status` as trailers
+/// when it does not classify the head alone.
+#[cfg(feature = "tower-http")]
+pub(crate) fn is_failure_with<C>(classifier: C, status: &StatusInfo) -> bool
+where C: tower_http::classify::ClassifyResponse {
+    use tower_http::classify::ClassifiedResponse;
+    use tower_http::classify::ClassifyEos;
+
+    let mut response = http::Response::new(());
+    *response.status_mut() = status.http_status;
+    if let Some(code) = status.grpc_status {
+        response
+            .headers_mut()
+            .insert(GRPC_STATUS, HeaderValue::from(code));
+    }
+    match classifier.classify_response(&response) {
+        ClassifiedResponse::Ready(result) => result.is_err(),
+        ClassifiedResponse::RequiresEos(eos) => eos.classify_eos(Some(response.headers())).is_err(),
+    }
+}
+
+/// Returns the `grpc-status` of a response head, present in Trailers-Only responses.
+pub(crate) fn grpc_status(headers: &HeaderMap) -> Option<u32> {
+    headers
+        .get(GRPC_STATUS)
+        .and_then(|code| code.to_str().ok())
+        .and_then(|code| code.parse().ok())
+}
+
+/// Embeds the trace id in the headers of an error response. Successful responses are left
+/// untouched.
+pub(crate) fn embed_trace_id(headers: &mut HeaderMap, trace_id: TraceId, mode: TraceIdInErrors) {
+    let Some(code) = grpc_status(headers) else {
+        return;
+    };
+    if code == 0 {
+        return;
+    }
+
+    let trace_id = format!("{:032x}", trace_id.0);
+    let value = match mode {
+        TraceIdInErrors::Message => {
+            let message = headers
+                .get(GRPC_MESSAGE)
+                .and_then(|message| message.to_str().ok())
+                .unwrap_or_default();
+            let message = if message.is_empty() {
+                format!("[trace_id={trace_id}]")
+            } else {
+                format!("{message} [trace_id={trace_id}]")
+            };
+            HeaderValue::try_from(message).map(|value| (GRPC_MESSAGE, value))
+        }
+        TraceIdInErrors::StatusDetails => {
+            // A protobuf message can be extended by appending fields, so the detail is appended
+            // to the existing `google.rpc.Status`, or to a new one carrying only the code.
+            let mut status = match headers.get(GRPC_STATUS_DETAILS) {
+                Some(details) => match decode_details(details) {
+                    Some(status) => status,
+                    None => return,
+                },
+                None => {
+                    let mut status = vec![0x08];
+                    encode_varint(code as u64, &mut status);
+                    status
+                }
+            };
+            encode_request_info_detail(&trace_id, &mut status);
+            HeaderValue::try_from(STANDARD_NO_PAD.encode(status))
+                .map(|value| (GRPC_STATUS_DETAILS, value))
+        }
+    };
+
+    if let Ok((name, value)) = value {
+        headers.insert(name, value);
+    }
+}
+
+/// Decodes a `grpc-status-details-bin` value, which may or may not be padded.
+fn decode_details(details: &HeaderValue) -> Option<Vec<u8>> {
+    let details = details.as_bytes();
+    let details = details.strip_suffix(b"==").unwrap_or(details);
+    let details = details.strip_suffix(b"=").unwrap_or(details);
+    STANDARD_NO_PAD.decode(details).ok()
+}
+
+/// Encodes `details: [Any { type_url, value: RequestInfo { request_id } }]` as field 3 of a
+/// `google.rpc.Status`.
+fn encode_request_info_detail(request_id: &str, buf: &mut Vec<u8>) {
+    let mut request_info = Vec::new();
+    encode_bytes_field(1, request_id.as_bytes(), &mut request_info);
+
+    let mut any = Vec::new();
+    encode_bytes_field(1, REQUEST_INFO_TYPE_URL.as_bytes(), &mut any);
+    encode_bytes_field(2, &request_info, &mut any);
+
+    encode_bytes_field(3, &any, buf);
+}
+
+fn encode_bytes_field(field: u64, bytes: &[u8], buf: &mut Vec<u8>) {
+    // Wire type 2: length-delimited.
+    encode_varint((field << 3) | 2, buf);
+    encode_varint(bytes.len() as u64, buf);
+    buf.extend_from_slice(bytes);
+}
+
+fn encode_varint(mut value: u64, buf: &mut Vec<u8>) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACE_ID: TraceId = TraceId(0x0af7651916cd43dd8448eb211c80319c);
+    const TRACE_ID_HEX: &[u8] = b"0af7651916cd43dd8448eb211c80319c";
+
+    fn error_headers(code: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(GRPC_STATUS, HeaderValue::from_static(code));
+        headers
+    }
+
+    /// The encoded `details` field holding the `RequestInfo` of `TRACE_ID`, written by hand.
+    fn request_info_detail() -> Vec<u8> {
+        let mut request_info = vec![0x0a, 32];
+        request_info.extend_from_slice(TRACE_ID_HEX);
+
+        let mut any = vec![0x0a, 42];
+        any.extend_from_slice(b"type.googleapis.com/google.rpc.RequestInfo");
+        any.extend_from_slice(&[0x12, 34]);
+        any.extend_from_slice(&request_info);
+
+        let mut detail = vec![0x1a, 80];
+        detail.extend_from_slice(&any);
+        detail
+    }
+
+    fn details(headers: &HeaderMap) -> Vec<u8> {
+        decode_details(&headers[GRPC_STATUS_DETAILS]).unwrap()
+    }
+
+    #[test]
+    fn encodes_varints() {
+        let mut buf = Vec::new();
+        encode_varint(1, &mut buf);
+        encode_varint(127, &mut buf);
+        encode_varint(128, &mut buf);
+        encode_varint(300, &mut buf);
+        assert_eq!(buf, [0x01, 0x7f, 0x80, 0x01, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn adds_status_with_request_info() {
+        let mut headers = error_headers("5");
+        embed_trace_id(&mut headers, TRACE_ID, TraceIdInErrors::StatusDetails);
+
+        let mut expected = vec![0x08, 0x05];
+        expected.extend(request_info_detail());
+        assert_eq!(details(&headers), expected);
+    }
+
+    #[test]
+    fn appends_request_info_to_existing_status() {
+        // `google.rpc.Status { code: 3, message: "bad" }`, padded.
+        let status = [0x08, 0x03, 0x12, 0x03, b'b', b'a', b'd'];
+        let mut headers = error_headers("3");
+        headers.insert(
+            GRPC_STATUS_DETAILS,
+            HeaderValue::try_from(base64::engine::general_purpose::STANDARD.encode(status))
+                .unwrap(),
+        );
+        embed_trace_id(&mut headers, TRACE_ID, TraceIdInErrors::StatusDetails);
+
+        let mut expected = status.to_vec();
+        expected.extend(request_info_detail());
+        assert_eq!(details(&headers), expected);
+    }
+
+    #[test]
+    fn invalid_details_are_left_untouched() {
+        let mut headers = error_headers("3");
+        headers.insert(GRPC_STATUS_DETAILS, HeaderValue::from_static("!!"));
+        embed_trace_id(&mut headers, TRACE_ID, TraceIdInErrors::StatusDetails);
+        assert_eq!(headers[GRPC_STATUS_DETAILS], "!!");
+    }
+
+    #[test]
+    fn appends_trace_id_to_message() {
+        let mut headers = error_headers("13");
+        headers.insert(GRPC_MESSAGE, HeaderValue::from_static("internal"));
+        embed_trace_id(&mut headers, TRACE_ID, TraceIdInErrors::Message);
+        assert_eq!(
+            headers[GRPC_MESSAGE],
+            "internal [trace_id=0af7651916cd43dd8448eb211c80319c]"
+        );
+
+        let mut headers = error_headers("13");
+        embed_trace_id(&mut headers, TRACE_ID, TraceIdInErrors::Message);
+        assert_eq!(
+            headers[GRPC_MESSAGE],
+            "[trace_id=0af7651916cd43dd8448eb211c80319c]"
+        );
+    }
+
+    #[test]
+    fn successful_responses_are_untouched() {
+        let mut headers = error_headers("0");
+        embed_trace_id(&mut headers, TRACE_ID, TraceIdInErrors::StatusDetails);
+        embed_trace_id(&mut headers, TRACE_ID, TraceIdInErrors::Message);
+        assert_eq!(headers.len(), 1);
+
+        let mut headers = HeaderMap::new();
+        embed_trace_id(&mut headers, TRACE_ID, TraceIdInErrors::Message);
+        assert!(headers.is_empty());
+    }
+}
@@ -0,0 +1,223 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::PoisonError;
+use std::time::Duration;
+use std::time::Instant;
+
+use fastrace::prelude::*;
+
+/// The number of latencies kept per method and interval to estimate the p99.
+const RESERVOIR_SIZE: usize = 1024;
+
+/// The number of methods summarized separately per interval. The path of a request is chosen by
+/// the client, so requests for further methods are summarized together under [`OTHER_METHODS`].
+const MAX_METHODS: usize = 256;
+
+const OTHER_METHODS: &str = "other";
+
+/// Aggregates requests per gRPC method and emits one summary span per method and interval.
+pub(crate) struct Summary {
+    interval: Duration,
+    current: Mutex<Interval>,
+}
+
+/// The requests of the current interval.
+struct Interval {
+    started: Instant,
+    methods: HashMap<String, Stats>,
+}
+
+/// A request being summarized.
+pub(crate) struct Pending {
+    method: String,
+    start: Instant,
+}
+
+struct Stats {
+    count: u64,
+    errors: u64,
+    latencies_us: Vec<u64>,
+    rng: u64,
+}
+
+impl Summary {
+    pub(crate) fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            current: Mutex::new(Interval {
+                started: Instant::now(),
+                methods: HashMap::new(),
+            }),
+        }
+    }
+
+    /// Starts summarizing a request, returning `None` for paths that are not gRPC methods.
+    pub(crate) fn start(path: &str) -> Option<Pending> {
+        let (service, method) = crate::name::grpc_method(path)?;
+        Some(Pending {
+            method: format!("{service}/{method}"),
+            start: Instant::now(),
+        })
+    }
+
+    /// Records a completed request, emitting the summary spans of all methods and starting a new
+    /// interval when the current one has elapsed.
+    pub(crate) fn finish(&self, pending: Pending, error: bool) {
+        let latency_us = pending.start.elapsed().as_micros() as u64;
+        let now = Instant::now();
+
+        let mut current = self.current.lock().unwrap_or_else(PoisonError::into_inner);
+        let methods = &mut current.methods;
+        let method = if methods.len() < MAX_METHODS || methods.contains_key(&pending.method) {
+            pending.method
+        } else {
+            OTHER_METHODS.to_owned()
+        };
+        methods
+            .entry(method)
+            .or_insert_with(Stats::new)
+            .record(latency_us, error);
+
+        let elapsed = now.duration_since(current.started);
+        if elapsed >= self.interval {
+            let methods = std::mem::take(&mut current.methods);
+            current.started = now;
+            drop(current);
+            for (method, stats) in methods {
+                stats.emit(&method, elapsed);
+            }
+        }
+    }
+}
+
+impl Stats {
+    fn new() -> Self {
+        Self {
+            count: 0,
+            errors: 0,
+            latencies_us: Vec::new(),
+            rng: 0x9e37_79b9_7f4a_7c15,
+        }
+    }
+
+    fn record(&mut self, latency_us: u64, error: bool) {
+        self.count += 1;
+        if error {
+            self.errors += 1;
+        }
+
+        // Reservoir sampling keeps a uniform sample of the latencies.
+        if self.latencies_us.len() < RESERVOIR_SIZE {
+            self.latencies_us.push(latency_us);
+        } else {
+            let slot = (self.next_random() % self.count) as usize;
+            if slot < RESERVOIR_SIZE {
+                self.latencies_us[slot] = latency_us;
+            }
+        }
+    }
+
+    fn next_random(&mut self) -> u64 {
+        // xorshift64
+        self.rng ^= self.rng << 13;
+        self.rng ^= self.rng >> 7;
+        self.rng ^= self.rng << 17;
+        self.rng
+    }
+
+    fn emit(mut self, method: &str, interval: Duration) {
+        let p99_us = p99(&mut self.latencies_us);
+        let interval_ms = interval.as_millis();
+
+        let span = Span::root(format!("summary {method}"), SpanContext::random());
+        span.add_properties(|| {
+            [
+                ("summary.count", self.count.to_string()),
+                ("summary.errors", self.errors.to_string()),
+                ("summary.p99_us", p99_us.to_string()),
+                ("summary.interval_ms", interval_ms.to_string()),
+            ]
+        });
+    }
+}
+
+/// Returns the nearest-rank p99 of `latencies`, sorting them, or 0 when empty.
+fn p99(latencies: &mut [u64]) -> u64 {
+    latencies.sort_unstable();
+    match latencies.len() {
+        0 => 0,
+        len => latencies[(len * 99).div_ceil(100) - 1],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn p99_is_nearest_rank() {
+        assert_eq!(p99(&mut []), 0);
+        assert_eq!(p99(&mut [7]), 7);
+        assert_eq!(p99(&mut [3, 1, 2]), 3);
+
+        let mut latencies: Vec<u64> = (1..=100).rev().collect();
+        assert_eq!(p99(&mut latencies), 99);
+        let mut latencies: Vec<u64> = (1..=1000).collect();
+        assert_eq!(p99(&mut latencies), 990);
+        let mut latencies: Vec<u64> = (1..=101).collect();
+        assert_eq!(p99(&mut latencies), 100);
+    }
+
+    #[test]
+    fn reservoir_is_bounded() {
+        let mut stats = Stats::new();
+        for latency in 0..(RESERVOIR_SIZE as u64 * 4) {
+            stats.record(latency, latency % 10 == 0);
+        }
+        assert_eq!(stats.count, RESERVOIR_SIZE as u64 * 4);
+        assert_eq!(stats.errors, RESERVOIR_SIZE as u64 * 4 / 10);
+        assert_eq!(stats.latencies_us.len(), RESERVOIR_SIZE);
+        // Later latencies replace some of the first ones.
+        assert!(
+            stats
+                .latencies_us
+                .iter()
+                .any(|&latency| latency >= RESERVOIR_SIZE as u64)
+        );
+    }
+
+    fn finish(summary: &Summary, path: &str) {
+        summary.finish(Summary::start(path).unwrap(), false);
+    }
+
+    #[test]
+    fn methods_beyond_the_cap_are_summarized_together() {
+        let summary = Summary::new(Duration::from_secs(3600));
+        for i in 0..MAX_METHODS + 10 {
+            finish(&summary, &format!("/pkg.Service/Method{i}"));
+        }
+        finish(&summary, "/pkg.Service/Method0");
+
+        let current = summary.current.lock().unwrap();
+        assert_eq!(current.methods.len(), MAX_METHODS + 1);
+        assert_eq!(current.methods["pkg.Service/Method0"].count, 2);
+        assert_eq!(current.methods[OTHER_METHODS].count, 10);
+    }
+
+    #[test]
+    fn methods_are_cleared_once_emitted() {
+        let summary = Summary::new(Duration::ZERO);
+        finish(&summary, "/pkg.Service/Method");
+        finish(&summary, "/pkg.Service/Other");
+
+        let current = summary.current.lock().unwrap();
+        assert!(current.methods.is_empty());
+    }
+
+    #[test]
+    fn only_grpc_methods_are_summarized() {
+        let pending = Summary::start("/pkg.Service/Method").unwrap();
+        assert_eq!(pending.method, "pkg.Service/Method");
+        assert!(Summary::start("/health").is_none());
+    }
+}
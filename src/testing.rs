@@ -0,0 +1,105 @@
+//! Helpers shared by the unit tests.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::pin::pin;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Once;
+use std::sync::PoisonError;
+use std::task::Context;
+use std::task::Poll;
+use std::task::Wake;
+use std::task::Waker;
+
+use fastrace::collector::Config;
+use fastrace::collector::Reporter;
+use fastrace::collector::SpanRecord;
+use fastrace::prelude::*;
+
+/// Spans reported by all tests, told apart by their trace id.
+static SPANS: Mutex<Vec<SpanRecord>> = Mutex::new(Vec::new());
+static INIT: Once = Once::new();
+
+struct TestReporter;
+
+impl Reporter for TestReporter {
+    fn report(&mut self, spans: Vec<SpanRecord>) {
+        let mut reported = SPANS.lock().unwrap_or_else(PoisonError::into_inner);
+        reported.extend(spans);
+    }
+}
+
+/// Installs the reporter collecting the spans of all tests.
+pub(crate) fn init() {
+    INIT.call_once(|| fastrace::set_reporter(TestReporter, Config::default()));
+}
+
+/// Flushes the collector and returns the spans reported for `trace_id`.
+pub(crate) fn spans(trace_id: TraceId) -> Vec<SpanRecord> {
+    fastrace::flush();
+    let reported = SPANS.lock().unwrap_or_else(PoisonError::into_inner);
+    reported
+        .iter()
+        .filter(|span| span.trace_id == trace_id)
+        .cloned()
+        .collect()
+}
+
+/// Flushes the collector and returns the spans named `name` reported by all tests, for spans
+/// starting a trace of their own.
+pub(crate) fn spans_named(name: &str) -> Vec<SpanRecord> {
+    fastrace::flush();
+    let reported = SPANS.lock().unwrap_or_else(PoisonError::into_inner);
+    reported
+        .iter()
+        .filter(|span| span.name == name)
+        .cloned()
+        .collect()
+}
+
+/// Returns the span named `name` among `spans`.
+pub(crate) fn span<'a>(spans: &'a [SpanRecord], name: &str) -> &'a SpanRecord {
+    spans
+        .iter()
+        .find(|span| span.name == name)
+        .unwrap_or_else(|| panic!("no `{name}` span in {spans:?}"))
+}
+
+/// Returns the value of the property `key` of `span`.
+pub(crate) fn property<'a>(span: &'a SpanRecord, key: &str) -> Option<&'a str> {
+    span.properties
+        .iter()
+        .find(|(k, _)| k == key)
+        .map(|(_, value)| value.as_ref())
+}
+
+/// Returns whether `span` has an event named `name`.
+pub(crate) fn has_event(span: &SpanRecord, name: &str) -> bool {
+    span.events.iter().any(|event| event.name == name)
+}
+
+struct NoopWaker;
+
+impl Wake for NoopWaker {
+    fn wake(self: Arc<Self>) {}
+}
+
+/// Polls `future` once.
+pub(crate) fn poll_once<F: Future>(future: Pin<&mut F>) -> Poll<F::Output> {
+    let waker = Waker::from(Arc::new(NoopWaker));
+    future.poll(&mut Context::from_waker(&waker))
+}
+
+/// Polls `future` on the current thread until it completes.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(NoopWaker));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = pin!(future);
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+        std::thread::yield_now();
+    }
+}
@@ -0,0 +1,87 @@
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+use fastrace::prelude::*;
+
+const GRPC_TIMEOUT: &str = "grpc-timeout";
+
+/// Records the `grpc-timeout` of a request as the `grpc.timeout_ms` property, the budget left
+/// when the span starts, and the `grpc.deadline_unix_ms` property, the deadline it implies.
+pub(crate) fn record_grpc_timeout(span: &Span, headers: &http::HeaderMap) {
+    let Some(timeout) = headers
+        .get(GRPC_TIMEOUT)
+        .and_then(|timeout| timeout.to_str().ok())
+        .and_then(parse_grpc_timeout)
+    else {
+        return;
+    };
+    let deadline = SystemTime::now()
+        .checked_add(timeout)
+        .and_then(|deadline| deadline.duration_since(UNIX_EPOCH).ok());
+
+    span.add_property(|| {
+        (
+            "grpc.timeout_ms",
+            format!("{:.3}", timeout.as_secs_f64() * 1e3),
+        )
+    });
+    if let Some(deadline) = deadline {
+        span.add_property(|| ("grpc.deadline_unix_ms", deadline.as_millis().to_string()));
+    }
+}
+
+/// Parses a `grpc-timeout` value: at most 8 digits followed by a unit, one of `H`, `M`, `S`,
+/// `m`, `u` or `n`.
+fn parse_grpc_timeout(timeout: &str) -> Option<Duration> {
+    let (value, unit) = timeout.split_at_checked(timeout.len().checked_sub(1)?)?;
+    if value.is_empty() || value.len() > 8 || !value.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let value: u64 = value.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(value * 3600)),
+        "M" => Some(Duration::from_secs(value * 60)),
+        "S" => Some(Duration::from_secs(value)),
+        "m" => Some(Duration::from_millis(value)),
+        "u" => Some(Duration::from_micros(value)),
+        "n" => Some(Duration::from_nanos(value)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_every_unit() {
+        assert_eq!(parse_grpc_timeout("2H"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_grpc_timeout("3M"), Some(Duration::from_secs(180)));
+        assert_eq!(parse_grpc_timeout("10S"), Some(Duration::from_secs(10)));
+        assert_eq!(parse_grpc_timeout("250m"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_grpc_timeout("7u"), Some(Duration::from_micros(7)));
+        assert_eq!(
+            parse_grpc_timeout("99999999n"),
+            Some(Duration::from_nanos(99_999_999))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        for timeout in [
+            "",
+            "S",
+            "100",
+            "1s",
+            "123456789S",
+            "-1S",
+            "+1S",
+            "1.5S",
+            " 1S",
+            "1é",
+        ] {
+            assert_eq!(parse_grpc_timeout(timeout), None, "{timeout:?}");
+        }
+    }
+}
@@ -0,0 +1,62 @@
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+/// A shared switch turning tracing on and off at runtime.
+///
+/// Clones share the same state, so a handle kept by the application can flip tracing for layers
+/// that were configured with it through
+/// [`ServerTracing::with_toggle`](crate::ServerTracing::with_toggle), without rebuilding the
+/// server. This allows disabling tracing instantly during incidents where the collector is the
+/// bottleneck.
+///
+/// ```
+/// use fastrace_tonic::FastraceToggle;
+///
+/// let toggle = FastraceToggle::new(true);
+/// let layer = fastrace_tonic::ServerTracing::new()
+///     .with_toggle(toggle.clone())
+///     .build();
+///
+/// // Later, from an admin endpoint:
+/// toggle.disable();
+/// ```
+#[derive(Clone, Debug)]
+pub struct FastraceToggle {
+    enabled: Arc<AtomicBool>,
+}
+
+impl FastraceToggle {
+    /// Creates a toggle in the given state.
+    pub fn new(enabled: bool) -> Self {
+        Self {
+            enabled: Arc::new(AtomicBool::new(enabled)),
+        }
+    }
+
+    /// Turns tracing on.
+    pub fn enable(&self) {
+        self.set(true);
+    }
+
+    /// Turns tracing off.
+    pub fn disable(&self) {
+        self.set(false);
+    }
+
+    /// Sets whether tracing is on.
+    pub fn set(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Returns whether tracing is on.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for FastraceToggle {
+    fn default() -> Self {
+        Self::new(true)
+    }
+}
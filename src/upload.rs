@@ -0,0 +1,391 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::OnceLock;
+use std::sync::Weak;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::task::Context;
+use std::task::Poll;
+use std::time::Duration;
+use std::time::Instant;
+
+use bytes::Buf;
+use fastrace::prelude::*;
+use http::Request;
+use http_body::Body;
+use http_body::Frame;
+use http_body::SizeHint;
+use tonic::body::BoxBody;
+use tower_layer::Layer;
+use tower_service::Service;
+
+use crate::client::ClientSpan;
+use crate::watchdog;
+
+/// Client layer for recording the progress of streaming request bodies.
+///
+/// Request bodies are watched once they first wait for data, so bodies that are finished or of
+/// exact size from the start, and bodies produced at once, such as those of unary calls, are
+/// passed through without a timer. From then on, every `interval` until the request body is
+/// finished, an `upload progress` event with the number of bytes and data frames sent so far is
+/// added, or an `upload stalled` event with the time since the last data frame as `stalled_ms`
+/// when nothing was sent during the interval, so a stalled client-streaming upload can be
+/// diagnosed while it is still in flight. The events are added from a background thread,
+/// independently of the body being polled.
+///
+/// The events are added to the client span of the request when this layer is added under a
+/// layer built with
+/// [`ClientTracing::build_with_client_span`](crate::ClientTracing::build_with_client_span).
+/// Otherwise they are added to an `upload` span, a child of the local parent current when the
+/// request was made, which ends once the request body is finished or dropped. Requests made
+/// without either are passed through.
+#[derive(Clone)]
+pub struct FastraceUploadProgressLayer {
+    interval: Duration,
+}
+
+impl FastraceUploadProgressLayer {
+    /// Creates a layer emitting progress events once per `interval`, of at least a millisecond.
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval: interval.max(Duration::from_millis(1)),
+        }
+    }
+}
+
+impl<S> Layer<S> for FastraceUploadProgressLayer {
+    type Service = FastraceUploadProgressService<S>;
+
+    fn layer(&self, service: S) -> Self::Service {
+        FastraceUploadProgressService {
+            service,
+            interval: self.interval,
+        }
+    }
+}
+
+/// Client-side service that records the progress of streaming request bodies.
+///
+/// See [`FastraceUploadProgressLayer`] for details.
+#[derive(Clone)]
+pub struct FastraceUploadProgressService<S> {
+    service: S,
+    interval: Duration,
+}
+
+impl<S> Service<Request<BoxBody>> for FastraceUploadProgressService<S>
+where S: Service<Request<BoxBody>>
+{
+    type Response = S::Response;
+    type Error = S::Error;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<BoxBody>) -> Self::Future {
+        if req.body().is_end_stream() || req.body().size_hint().exact().is_some() {
+            return self.service.call(req);
+        }
+        let target = match req.extensions().get::<ClientSpan>() {
+            Some(client_span) => Target::Client(client_span.0.clone()),
+            None => match SpanContext::current_local_parent() {
+                Some(parent) => Target::Parent(parent),
+                None => return self.service.call(req),
+            },
+        };
+        let interval = self.interval;
+        let req =
+            req.map(|body| tonic::body::boxed(UploadProgressBody::new(body, target, interval)));
+
+        self.service.call(req)
+    }
+}
+
+/// Where the events of a watched upload are added.
+enum Target {
+    /// The client span of the request.
+    Client(Weak<Span>),
+    /// The parent of the `upload` span created once the upload is watched.
+    Parent(SpanContext),
+}
+
+/// The span the events of an upload are added to.
+enum UploadSpan {
+    Client(Weak<Span>),
+    Upload(Span),
+}
+
+impl UploadSpan {
+    fn add_event(&self, event: Event) {
+        match self {
+            // The client span has ended along with the response, so the events are dropped.
+            Self::Client(span) => {
+                if let Some(span) = span.upgrade() {
+                    span.add_event(event);
+                }
+            }
+            Self::Upload(span) => span.add_event(event),
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    struct UploadProgressBody<B> {
+        #[pin]
+        inner: B,
+        progress: Arc<Progress>,
+        // Taken once the body first waits for data and the upload is watched.
+        watch: Option<(Target, Duration)>,
+    }
+}
+
+/// The progress of an upload, shared with the ticks emitting progress events.
+struct Progress {
+    /// Set once the upload is watched.
+    span: OnceLock<UploadSpan>,
+    start: Instant,
+    /// The time of the last data frame, in microseconds since `start`.
+    last_frame_us: AtomicU64,
+    bytes: AtomicU64,
+    frames: AtomicU64,
+    finished: AtomicBool,
+}
+
+impl<B> UploadProgressBody<B> {
+    fn new(inner: B, target: Target, interval: Duration) -> Self {
+        let progress = Arc::new(Progress {
+            span: OnceLock::new(),
+            start: Instant::now(),
+            last_frame_us: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+            frames: AtomicU64::new(0),
+            finished: AtomicBool::new(false),
+        });
+        Self {
+            inner,
+            progress,
+            watch: Some((target, interval)),
+        }
+    }
+}
+
+impl Progress {
+    /// Starts emitting progress events every `interval`.
+    fn watch(self: &Arc<Self>, target: Target, interval: Duration) {
+        let span = match target {
+            Target::Client(span) => UploadSpan::Client(span),
+            Target::Parent(parent) => UploadSpan::Upload(Span::root("upload", parent)),
+        };
+        let _ = self.span.set(span);
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        schedule_tick(Arc::downgrade(self), interval, bytes);
+    }
+
+    /// Adds the event `name` with the progress so far, once the upload is watched.
+    fn add_event(&self, name: &'static str) {
+        let Some(span) = self.span.get() else {
+            return;
+        };
+        let bytes = self.bytes.load(Ordering::Relaxed);
+        let frames = self.frames.load(Ordering::Relaxed);
+        add_progress_event(span, Event::new(name), bytes, frames);
+    }
+}
+
+/// Emits the progress made since the last tick, `last_bytes` bytes having been sent by then, and
+/// schedules the next tick.
+fn schedule_tick(progress: Weak<Progress>, interval: Duration, last_bytes: u64) {
+    watchdog::schedule(Instant::now() + interval, move || {
+        // The body is gone, and the span with it.
+        let Some(current) = progress.upgrade() else {
+            return;
+        };
+        if current.finished.load(Ordering::Relaxed) {
+            return;
+        }
+
+        let Some(span) = current.span.get() else {
+            return;
+        };
+        let bytes = current.bytes.load(Ordering::Relaxed);
+        let frames = current.frames.load(Ordering::Relaxed);
+        let event = if bytes == last_bytes {
+            let last_frame = Duration::from_micros(current.last_frame_us.load(Ordering::Relaxed));
+            let stalled_ms = current
+                .start
+                .elapsed()
+                .saturating_sub(last_frame)
+                .as_millis();
+            Event::new("upload stalled").with_property(|| ("stalled_ms", stalled_ms.to_string()))
+        } else {
+            Event::new("upload progress")
+        };
+        add_progress_event(span, event, bytes, frames);
+        drop(current);
+
+        schedule_tick(progress, interval, bytes);
+    });
+}
+
+impl<B: Body> Body for UploadProgressBody<B> {
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_frame(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+        let this = self.project();
+        let frame = match this.inner.poll_frame(cx) {
+            Poll::Ready(frame) => frame,
+            Poll::Pending => {
+                if let Some((target, interval)) = this.watch.take() {
+                    this.progress.watch(target, interval);
+                }
+                return Poll::Pending;
+            }
+        };
+
+        let progress = this.progress;
+        match &frame {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    progress
+                        .bytes
+                        .fetch_add(data.remaining() as u64, Ordering::Relaxed);
+                    progress.frames.fetch_add(1, Ordering::Relaxed);
+                    let last_frame_us = progress.start.elapsed().as_micros() as u64;
+                    progress
+                        .last_frame_us
+                        .store(last_frame_us, Ordering::Relaxed);
+                }
+            }
+            Some(Err(_)) => {
+                progress.finished.store(true, Ordering::Relaxed);
+                progress.add_event("upload failed");
+            }
+            None => {
+                progress.finished.store(true, Ordering::Relaxed);
+                progress.add_event("upload complete");
+            }
+        }
+
+        Poll::Ready(frame)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn add_progress_event(span: &UploadSpan, event: Event, bytes: u64, frames: u64) {
+    span.add_event(
+        event.with_properties(move || {
+            [("bytes", bytes.to_string()), ("frames", frames.to_string())]
+        }),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::future::Ready;
+    use std::sync::Mutex;
+
+    use bytes::Bytes;
+
+    use super::*;
+    use crate::testing;
+
+    /// Records the body of the last request.
+    #[derive(Clone, Default)]
+    struct Capture {
+        body: Arc<Mutex<Option<BoxBody>>>,
+    }
+
+    impl Service<Request<BoxBody>> for Capture {
+        type Response = ();
+        type Error = Infallible;
+        type Future = Ready<Result<Self::Response, Self::Error>>;
+
+        fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, req: Request<BoxBody>) -> Self::Future {
+            *self.body.lock().unwrap() = Some(req.into_body());
+            std::future::ready(Ok(()))
+        }
+    }
+
+    /// A body waiting once before sending a single message.
+    #[derive(Default)]
+    struct Streaming {
+        waited: bool,
+        sent: bool,
+    }
+
+    impl Body for Streaming {
+        type Data = Bytes;
+        type Error = Infallible;
+
+        fn poll_frame(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+        ) -> Poll<Option<Result<Frame<Self::Data>, Self::Error>>> {
+            if !self.waited {
+                self.waited = true;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            if !self.sent {
+                self.sent = true;
+                return Poll::Ready(Some(Ok(Frame::data(Bytes::from_static(b"message")))));
+            }
+            Poll::Ready(None)
+        }
+    }
+
+    /// Sends `body` under a new trace, reads it to the end and returns the spans of the trace.
+    fn upload(body: BoxBody) -> Vec<fastrace::collector::SpanRecord> {
+        testing::init();
+        let parent = SpanContext::random();
+        let capture = Capture::default();
+        {
+            let root = Span::root("request", parent);
+            let _guard = root.set_local_parent();
+            let mut service =
+                FastraceUploadProgressLayer::new(Duration::from_secs(60)).layer(capture.clone());
+            testing::block_on(service.call(Request::new(body))).unwrap();
+        }
+
+        let mut body = capture.body.lock().unwrap().take().unwrap();
+        while testing::block_on(std::future::poll_fn(|cx| {
+            Pin::new(&mut body).poll_frame(cx)
+        }))
+        .is_some()
+        {}
+        drop(body);
+        testing::spans(parent.trace_id)
+    }
+
+    #[test]
+    fn waiting_bodies_are_watched() {
+        let spans = upload(tonic::body::boxed(Streaming::default()));
+        let span = testing::span(&spans, "upload");
+        assert!(testing::has_event(span, "upload complete"));
+    }
+
+    #[test]
+    fn bodies_of_exact_size_are_passed_through() {
+        let spans = upload(tonic::body::boxed(String::from("message")));
+        assert!(spans.iter().all(|span| span.name != "upload"));
+    }
+}
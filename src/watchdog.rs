@@ -0,0 +1,109 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::sync::Condvar;
+use std::sync::Mutex;
+use std::sync::Once;
+use std::sync::PoisonError;
+use std::time::Duration;
+use std::time::Instant;
+
+use fastrace::prelude::*;
+
+use crate::RequestSpan;
+
+/// Tasks waiting for their deadline, the earliest first.
+static QUEUE: Mutex<BinaryHeap<Entry>> = Mutex::new(BinaryHeap::new());
+static WAKE: Condvar = Condvar::new();
+static START: Once = Once::new();
+
+struct Entry {
+    deadline: Instant,
+    task: Box<dyn FnOnce() + Send>,
+}
+
+/// Watches a recorded request, emitting a `slow request` span in its trace if it is still in
+/// flight after `threshold`.
+///
+/// The span is a separate root whose parent is the request span, so it is submitted right away
+/// rather than with the request span, surfacing stuck requests while they are still running.
+pub(crate) fn watch(span: &RequestSpan, threshold: Duration) {
+    let Some(context) = SpanContext::from_span(span).filter(|context| context.sampled) else {
+        return;
+    };
+    let span = span.downgrade();
+    schedule(Instant::now() + threshold, move || {
+        if span.is_in_flight() {
+            let threshold_ms = threshold.as_millis().to_string();
+            let _span = Span::root("slow request", context)
+                .with_property(|| ("threshold_ms", threshold_ms));
+        }
+    });
+}
+
+/// Runs `task` on the watchdog thread once `deadline` has passed.
+///
+/// Tasks run one at a time and must not block. A task may schedule further tasks.
+pub(crate) fn schedule(deadline: Instant, task: impl FnOnce() + Send + 'static) {
+    START.call_once(|| {
+        // Without the thread, tasks simply never run.
+        let _ = std::thread::Builder::new()
+            .name("fastrace-tonic-watchdog".to_owned())
+            .spawn(run);
+    });
+
+    let mut queue = QUEUE.lock().unwrap_or_else(PoisonError::into_inner);
+    queue.push(Entry {
+        deadline,
+        task: Box::new(task),
+    });
+    WAKE.notify_one();
+}
+
+fn run() {
+    let mut queue = QUEUE.lock().unwrap_or_else(PoisonError::into_inner);
+    loop {
+        let now = Instant::now();
+        match queue.peek() {
+            None => {
+                queue = WAKE.wait(queue).unwrap_or_else(PoisonError::into_inner);
+            }
+            Some(entry) if entry.deadline > now => {
+                let timeout = entry.deadline - now;
+                queue = WAKE
+                    .wait_timeout(queue, timeout)
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .0;
+            }
+            Some(_) => {
+                let Some(entry) = queue.pop() else {
+                    continue;
+                };
+                // Released while the task runs, so it can schedule further tasks.
+                drop(queue);
+                (entry.task)();
+                queue = QUEUE.lock().unwrap_or_else(PoisonError::into_inner);
+            }
+        }
+    }
+}
+
+impl PartialEq for Entry {
+    fn eq(&self, other: &Self) -> bool {
+        self.deadline == other.deadline
+    }
+}
+
+impl Eq for Entry {}
+
+impl PartialOrd for Entry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Entry {
+    fn cmp(&self, other: &Self) -> Ordering {
+        // Reversed, so the max-heap yields the earliest deadline.
+        other.deadline.cmp(&self.deadline)
+    }
+}
@@ -0,0 +1,258 @@
+//! Wire compatibility fixtures for the propagation formats.
+//!
+//! The crate ships a set of fixtures pairing propagation headers with the span context they
+//! decode to, in `fixtures/wire-compat.txt`, also available as [`FIXTURES`]. Fixtures are never
+//! changed once released, so verifying a copy of them taken from the version you depend on means
+//! the functions of [`propagation`](crate::propagation) still decode and encode byte-identical
+//! headers.
+//!
+//! To pin propagation behavior across upgrades, vendor the fixture file into your repository and
+//! verify it from a test. Verifying [`FIXTURES`] itself only checks the crate against its own
+//! fixtures, which cannot catch a change between versions.
+//!
+//! ```
+//! // In your repository: `include_str!("wire-compat.txt")`, copied from the version pinned.
+//! # let vendored = fastrace_tonic::wire_compat::FIXTURES;
+//! fastrace_tonic::wire_compat::verify(vendored).unwrap();
+//! ```
+
+use std::fmt;
+
+use fastrace::prelude::*;
+use http::HeaderMap;
+
+use crate::propagation;
+
+/// The fixture file, as shipped with this version of the crate.
+///
+/// This is the file to vendor; see the [module documentation](self).
+pub const FIXTURES: &str = include_str!("../fixtures/wire-compat.txt");
+
+/// A propagation format covered by the fixtures.
+#[non_exhaustive]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// W3C Trace Context.
+    W3c,
+    /// B3.
+    B3,
+}
+
+/// A fixture pairing propagation headers with the span context they decode to.
+#[derive(Clone, Debug)]
+pub struct Fixture {
+    /// The name of the fixture.
+    pub name: String,
+    /// The format of the headers.
+    pub format: Format,
+    /// The headers, in the order they are listed in the fixture file.
+    pub headers: Vec<(String, String)>,
+    /// The span context the headers decode to.
+    pub span_context: SpanContext,
+    /// Whether the headers are only decoded, because encoding the span context produces
+    /// another representation.
+    pub decode_only: bool,
+}
+
+/// A fixture that failed to parse or verify.
+#[derive(Clone, Debug)]
+pub struct WireCompatError {
+    fixture: String,
+    message: String,
+}
+
+impl fmt::Display for WireCompatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "fixture `{}`: {}", self.fixture, self.message)
+    }
+}
+
+impl std::error::Error for WireCompatError {}
+
+/// Parses the fixtures shipped with the crate.
+pub fn fixtures() -> Result<Vec<Fixture>, WireCompatError> {
+    parse(FIXTURES)
+}
+
+/// Parses `fixtures`, in the format of the fixture file, and verifies every fixture.
+///
+/// Pass a copy of the fixture file vendored from the version you pinned, so that an upgrade
+/// changing propagation behavior fails.
+pub fn verify(fixtures: &str) -> Result<(), WireCompatError> {
+    parse(fixtures)?.iter().try_for_each(verify_fixture)
+}
+
+/// Verifies that the headers of `fixture` decode to its span context and, unless the fixture is
+/// decode-only, that the span context encodes to exactly its headers.
+pub fn verify_fixture(fixture: &Fixture) -> Result<(), WireCompatError> {
+    let error = |message: String| WireCompatError {
+        fixture: fixture.name.clone(),
+        message,
+    };
+
+    let mut headers = HeaderMap::new();
+    for (name, value) in &fixture.headers {
+        let name = http::HeaderName::try_from(name.as_str())
+            .map_err(|_| error(format!("invalid header name `{name}`")))?;
+        let value = http::HeaderValue::try_from(value.as_str())
+            .map_err(|_| error(format!("invalid header value `{value}`")))?;
+        headers.insert(name, value);
+    }
+
+    let decoded = match fixture.format {
+        Format::W3c => propagation::extract_w3c(&headers),
+        Format::B3 => propagation::extract_b3(&headers),
+    };
+    let expected = &fixture.span_context;
+    let matches = decoded.as_ref().is_some_and(|decoded| {
+        decoded.trace_id == expected.trace_id
+            && decoded.span_id == expected.span_id
+            && decoded.sampled == expected.sampled
+    });
+    if !matches {
+        return Err(error(format!("decoded {decoded:?}, expected {expected:?}")));
+    }
+
+    if !fixture.decode_only {
+        let mut encoded = HeaderMap::new();
+        match fixture.format {
+            Format::W3c => propagation::inject_w3c(expected, &mut encoded),
+            Format::B3 => propagation::inject_b3(expected, &mut encoded),
+        }
+        if encoded != headers {
+            return Err(error(format!("encoded {encoded:?}, expected {headers:?}")));
+        }
+    }
+
+    Ok(())
+}
+
+/// Parses fixtures in the format of the fixture file.
+pub fn parse(input: &str) -> Result<Vec<Fixture>, WireCompatError> {
+    let mut fixtures = Vec::new();
+    let mut current: Option<(String, Vec<(String, String)>)> = None;
+
+    for line in input.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(name) = line
+            .strip_prefix('[')
+            .and_then(|line| line.strip_suffix(']'))
+        {
+            if let Some((name, entries)) = current.take() {
+                fixtures.push(build(name, entries)?);
+            }
+            current = Some((name.to_owned(), Vec::new()));
+            continue;
+        }
+
+        let Some((_, entries)) = &mut current else {
+            return Err(WireCompatError {
+                fixture: String::new(),
+                message: format!("entry outside of a fixture: `{line}`"),
+            });
+        };
+        let Some((key, value)) = line.split_once('=') else {
+            return Err(WireCompatError {
+                fixture: String::new(),
+                message: format!("invalid entry: `{line}`"),
+            });
+        };
+        entries.push((key.trim().to_owned(), value.trim().to_owned()));
+    }
+    if let Some((name, entries)) = current {
+        fixtures.push(build(name, entries)?);
+    }
+
+    Ok(fixtures)
+}
+
+fn build(name: String, entries: Vec<(String, String)>) -> Result<Fixture, WireCompatError> {
+    let error = |message: String| WireCompatError {
+        fixture: name.clone(),
+        message,
+    };
+
+    let mut format = None;
+    let mut decode_only = false;
+    let mut trace_id = None;
+    let mut span_id = None;
+    let mut sampled = None;
+    let mut headers = Vec::new();
+    for (key, value) in entries {
+        match key.as_str() {
+            "format" => {
+                format = Some(match value.as_str() {
+                    "w3c" => Format::W3c,
+                    "b3" => Format::B3,
+                    _ => return Err(error(format!("unknown format `{value}`"))),
+                })
+            }
+            "decode_only" => decode_only = value == "true",
+            "trace_id" => trace_id = u128::from_str_radix(&value, 16).ok().map(TraceId),
+            "span_id" => span_id = u64::from_str_radix(&value, 16).ok().map(SpanId),
+            "sampled" => sampled = Some(value == "true"),
+            _ => headers.push((key, value)),
+        }
+    }
+
+    let (Some(format), Some(trace_id), Some(span_id), Some(sampled)) =
+        (format, trace_id, span_id, sampled)
+    else {
+        return Err(error(
+            "missing or invalid `format`, `trace_id`, `span_id` or `sampled`".to_owned(),
+        ));
+    };
+    let mut span_context = SpanContext::new(trace_id, span_id);
+    span_context.sampled = sampled;
+
+    Ok(Fixture {
+        name,
+        format,
+        headers,
+        span_context,
+        decode_only,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn shipped_fixtures_verify() {
+        let fixtures = fixtures().unwrap();
+        assert!(!fixtures.is_empty());
+        for fixture in &fixtures {
+            verify_fixture(fixture).unwrap();
+        }
+    }
+
+    #[test]
+    fn changed_encoding_fails() {
+        let fixtures = "
+            [w3c sampled]
+            format = w3c
+            traceparent = 00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01
+            trace_id = 0af7651916cd43dd8448eb211c80319c
+            span_id = b7ad6b7169203331
+            sampled = false
+        ";
+        let err = verify(fixtures).unwrap_err();
+        assert!(err.to_string().starts_with("fixture `w3c sampled`"));
+    }
+
+    #[test]
+    fn incomplete_fixture_fails_to_parse() {
+        let fixtures = "
+            [missing span id]
+            format = b3
+            trace_id = 80f198ee56343ba864fe8b2a57d3eff7
+            sampled = true
+        ";
+        assert!(parse(fixtures).is_err());
+        assert!(parse("format = w3c").is_err());
+    }
+}